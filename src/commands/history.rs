@@ -0,0 +1,176 @@
+use std::borrow::Cow;
+
+use chrono::{TimeZone, Utc};
+use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOptionValue};
+use serenity::model::channel::AttachmentType;
+use serenity::utils::Color;
+use tracing::error;
+use crate::commands::truncate;
+use crate::json::HistoryJson;
+use crate::music::history::PlayedTrack;
+use crate::state::AppState;
+
+pub const HISTORY_CMD_NAME: &str = "history";
+pub const HISTORY_CMD_DESC: &str = "Tracks played in this server";
+
+const PAGE_SIZE: usize = 10;
+const MAX_TITLE_LEN: usize = 60;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(HISTORY_CMD_NAME).description(HISTORY_CMD_DESC)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("list")
+            .description("Show the history, newest first")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("page")
+                .description("Page to show, the first by default")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .required(false)))
+        .create_option(|option| option
+            .name("export")
+            .description("Download the whole history as a file")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("format")
+                .description("File format, csv by default")
+                .kind(CommandOptionType::String)
+                .add_string_choice("csv", "csv")
+                .add_string_choice("json", "json")
+                .required(false)))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let subcommand = match interaction.data.options.first() {
+        None => return,
+        Some(subcommand) => subcommand
+    };
+    let option = subcommand.options.first().and_then(|option| option.resolved.as_ref());
+
+    let state = AppState::from_context(&ctx).await;
+    let history = match state.guild(guild_id) {
+        None => vec![],
+        Some(handle) => handle.history.read().iter().cloned().collect::<Vec<PlayedTrack>>()
+    };
+
+    if subcommand.name == "export" {
+        if history.is_empty() {
+            return respond_error(&ctx, &interaction, String::from("❌ Nothing has been played yet")).await;
+        }
+        let (data, filename) = match option {
+            Some(CommandDataOptionValue::String(format)) if format == "json" => (export_json(&history), format!("history-{}.json", guild_id)),
+            _ => (export_csv(&history).into_bytes(), format!("history-{}.csv", guild_id))
+        };
+        let result = interaction.create_interaction_response(&ctx.http, |response| response
+            .interaction_response_data(|data_builder| data_builder
+                .ephemeral(true)
+                .content(format!("{} plays, oldest first", history.len()))
+                .add_file(AttachmentType::Bytes { data: Cow::Owned(data), filename }))).await;
+        if let Err(err) = result {
+            error!("Unable to send history export: {}", err);
+        }
+        return;
+    }
+
+    let page = match option {
+        Some(CommandDataOptionValue::Integer(page)) => (*page).max(1) as usize,
+        _ => 1
+    };
+    let embed = page_embed(&history, page, state.config.embed_color());
+    let result = interaction.create_interaction_response(&ctx.http, |response| response
+        .interaction_response_data(|data| data.add_embed(embed))).await;
+    if let Err(err) = result {
+        error!("Unable to respond to /history: {}", err);
+    }
+}
+
+async fn respond_error(ctx: &Context, interaction: &ApplicationCommandInteraction, message: String) {
+    let result = interaction.create_interaction_response(&ctx.http, |response| response
+        .interaction_response_data(|data| data.ephemeral(true).content(message))).await;
+    if let Err(err) = result {
+        error!("Unable to respond to /history: {}", err);
+    }
+}
+
+/// Newest first, each play shows the number /replay takes
+fn page_embed(history: &[PlayedTrack], page: usize, color: Color) -> CreateEmbed {
+    let pages = ((history.len() + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+    // Past the end shows the last page rather than nothing
+    let page = page.min(pages);
+    let lines = history.iter()
+        .skip((page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|play| format!("`{}.` {}", play.number, describe(play)))
+        .collect::<Vec<String>>();
+
+    let mut embed = CreateEmbed::default();
+    embed.title("Play history")
+        .color(color)
+        .description(if lines.is_empty() { String::from("Nothing has been played yet") } else { lines.join("\n") })
+        .footer(|footer| footer.text(format!("Page {} of {} · {} plays · /replay <number> to queue one again", page, pages, history.len())));
+    embed
+}
+
+fn describe(play: &PlayedTrack) -> String {
+    let title = truncate(play.title.as_deref().unwrap_or("Unknown title"), MAX_TITLE_LEN);
+    let mut line = match &play.url {
+        Some(url) => format!("[{}]({})", title, url),
+        None => title
+    };
+    if let Some(secs) = play.duration_secs {
+        line.push_str(&format!(" `{}`", format_duration(secs)));
+    }
+    if let Some(requester) = play.requester {
+        line.push_str(&format!(" · <@{}>", requester));
+    }
+    line.push_str(&format!(" · <t:{}:R>", play.played_at));
+    line
+}
+
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 { format!("{}:{:02}:{:02}", hours, minutes, secs) } else { format!("{}:{:02}", minutes, secs) }
+}
+
+/// Same layout the json store uses, oldest first
+fn export_json(history: &[PlayedTrack]) -> Vec<u8> {
+    let plays = history.iter().rev().map(PlayedTrack::to_json).collect::<Vec<HistoryJson>>();
+    serde_json::to_vec_pretty(&plays).unwrap_or_default()
+}
+
+fn export_csv(history: &[PlayedTrack]) -> String {
+    let mut csv = String::from("played_at,requester_id,title,url,duration_secs\n");
+    for play in history.iter().rev() {
+        let played_at = Utc.timestamp_opt(play.played_at, 0).single()
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_else(|| play.played_at.to_string());
+        let fields = [
+            played_at,
+            play.requester.map(|user_id| user_id.to_string()).unwrap_or_default(),
+            play.title.clone().unwrap_or_default(),
+            play.url.clone().unwrap_or_default(),
+            play.duration_secs.map(|secs| secs.to_string()).unwrap_or_default()
+        ];
+        csv.push_str(&fields.iter().map(|field| csv_field(field)).collect::<Vec<String>>().join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quotes fields that would otherwise break the row, as RFC 4180 describes
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
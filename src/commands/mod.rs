@@ -1,14 +1,44 @@
 use std::sync::Arc;
 use serenity::builder::CreateInteractionResponse;
+use serenity::client::Context;
 use serenity::http::Http;
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
 use serenity::model::prelude::command::Command;
 use serenity::prelude::SerenityError;
+use tracing::error;
 use tracing::log::{Level, log};
+use crate::config::Config;
 
+pub mod history;
+pub mod play;
+pub mod prefix;
+pub mod prefs;
+pub mod queue_link;
+pub mod replay;
 pub mod setup;
+pub mod stats;
+pub mod stay;
 
-pub async fn register_commands(http: &Arc<Http>) -> Result<(), SerenityError> {
-    Ok(log!(Level::Info, "Command Registered {:?}", Command::create_global_application_command(http, |b| setup::register(b)).await?))
+pub async fn register_commands(http: &Arc<Http>, config: &Config) -> Result<(), SerenityError> {
+    let features = config.features.clone();
+    Ok(log!(Level::Info, "Commands Registered {:?}", Command::set_global_application_commands(http, |commands| {
+        commands.create_application_command(|b| play::register(b));
+        commands.create_application_command(|b| queue_link::register(b));
+        commands.create_application_command(|b| prefs::register(b));
+        commands.create_application_command(|b| history::register(b));
+        commands.create_application_command(|b| replay::register(b));
+        if features.music_channel {
+            commands.create_application_command(|b| setup::register(b));
+        }
+        if features.stay_connected {
+            commands.create_application_command(|b| stay::register(b));
+        }
+        if features.member_tracking {
+            commands.create_application_command(|b| stats::register(b));
+        }
+        commands
+    }).await?))
 }
 
 pub fn interaction_msg_response(message: &str, ephemeral: bool) -> CreateInteractionResponse {
@@ -18,4 +48,31 @@ pub fn interaction_msg_response(message: &str, ephemeral: bool) -> CreateInterac
         .content(message)
     );
     response
-}
\ No newline at end of file
+}
+
+/// Acknowledges a command that needs more than discord's three seconds, finish it with [edit_response]
+pub async fn defer_ephemeral(ctx: &Context, interaction: &ApplicationCommandInteraction) {
+    let result = interaction.create_interaction_response(&ctx.http, |response| response
+        .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        .interaction_response_data(|data| data.ephemeral(true))
+    ).await;
+    if let Err(err) = result {
+        error!("Unable to defer /{}: {}", interaction.data.name, err);
+    }
+}
+
+pub async fn edit_response(ctx: &Context, interaction: &ApplicationCommandInteraction, message: &str) {
+    if let Err(err) = interaction.edit_original_interaction_response(&ctx.http, |response| response.content(message)).await {
+        error!("Unable to respond to /{}: {}", interaction.data.name, err);
+    }
+}
+
+/// Cuts `text` down to `max_chars`, marking the cut with an ellipsis
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max_chars - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
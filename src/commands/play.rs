@@ -0,0 +1,97 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOptionValue};
+use serenity::model::application::interaction::autocomplete::AutocompleteInteraction;
+use tracing::error;
+use crate::commands::{defer_ephemeral, edit_response, truncate};
+use crate::error::AyakaError;
+use crate::guild::player::PlayerId;
+use crate::interaction::{queue_and_play, resolve_request};
+use crate::music::history::suggest;
+use crate::state::AppState;
+
+pub const PLAY_CMD_NAME: &str = "play";
+pub const PLAY_CMD_DESC: &str = "Queue a song by name or link";
+
+/// Discord shows at most 25 choices
+const MAX_SUGGESTIONS: usize = 25;
+/// Discord's limit for both the name and the value of a choice
+const MAX_CHOICE_LEN: usize = 100;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(PLAY_CMD_NAME).description(PLAY_CMD_DESC)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("query")
+            .description("Song name or link, recently played songs are suggested")
+            .kind(CommandOptionType::String)
+            .set_autocomplete(true)
+            .required(true))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let query = match interaction.data.options.iter().find(|option| option.name == "query").and_then(|option| option.resolved.as_ref()) {
+        Some(CommandDataOptionValue::String(query)) => query.clone(),
+        _ => return
+    };
+    // Searching can take longer than discord waits for a response
+    defer_ephemeral(&ctx, &interaction).await;
+
+    let state = AppState::from_context(&ctx).await;
+    let (job_state, channel_id, author) = (state.clone(), interaction.channel_id, interaction.user.id);
+    let result = match resolve_request(&state, query.clone()).await {
+        Err(err) => Err(err),
+        Ok(source) => state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+            let player = guild.player_in(channel_id).unwrap_or(PlayerId::Default);
+            queue_and_play(guild, player, &job_state, author, source).await
+        })).await.unwrap_or(Err(AyakaError::GuildUnavailable))
+    };
+
+    let response = match result {
+        Ok(_) => format!("Queued `{}`", query),
+        Err(err) => {
+            error!("/play failed in {}: {}", guild_id, err);
+            err.user_message()
+        }
+    };
+    edit_response(&ctx, &interaction, &response).await;
+}
+
+/// Suggests recently played tracks, the value is the track's url so picking one plays exactly that track.
+/// Only the play history is searched, there are no playlists or library index to suggest from.
+pub async fn autocomplete(ctx: Context, autocomplete: AutocompleteInteraction) {
+    let guild_id = match autocomplete.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let query = autocomplete.data.options.iter()
+        .find(|option| option.focused)
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let state = AppState::from_context(&ctx).await;
+    let suggestions = match state.guild(guild_id) {
+        None => vec![],
+        Some(handle) => suggest(&query, handle.history.read().iter(), MAX_SUGGESTIONS)
+    };
+
+    let result = autocomplete.create_autocomplete_response(&ctx.http, |response| {
+        for track in suggestions {
+            let name = truncate(&track.title, MAX_CHOICE_LEN);
+            // Too long to send back as is, searching the title finds the same track in most cases
+            let value = if track.url.chars().count() <= MAX_CHOICE_LEN { track.url } else { truncate(&track.title, MAX_CHOICE_LEN) };
+            response.add_string_choice(name, value);
+        }
+        response
+    }).await;
+    if let Err(err) = result {
+        error!("Unable to send /play suggestions: {}", err);
+    }
+}
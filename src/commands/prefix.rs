@@ -0,0 +1,285 @@
+use std::collections::HashSet;
+
+use serenity::client::Context;
+use serenity::framework::StandardFramework;
+use serenity::framework::standard::{Args, CommandGroup, CommandResult, DispatchError, HelpOptions, help_commands};
+use serenity::framework::standard::macros::{command, group, help, hook};
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, UserId};
+use tracing::error;
+
+use crate::error::AyakaError;
+use crate::guild::GuildManager;
+use crate::guild::player::PlayerId;
+use crate::interaction::{apply_control, delete_later, queue_and_play, resolve_request, send_temporary_reply};
+use crate::state::AppState;
+
+/// Longest prefix a guild can pick, anything longer is more typing than a slash command
+const MAX_PREFIX_LEN: usize = 5;
+/// Upcoming songs listed by the queue command
+const QUEUE_PAGE_SIZE: usize = 10;
+
+#[group]
+#[description = "Same controls as the music embed"]
+#[only_in(guilds)]
+#[commands(play, skip, previous, stop, toggle_loop, shuffle, queue, jump)]
+struct Music;
+
+#[group]
+#[only_in(guilds)]
+#[commands(prefix)]
+struct Settings;
+
+/// Text commands are matched against the guild's own prefix only, the configured one is its default
+pub fn framework() -> StandardFramework {
+    StandardFramework::new()
+        .configure(|c| c
+            // A static prefix would keep working after a guild changed theirs
+            .prefix("")
+            .dynamic_prefix(guild_prefix)
+            .allow_dm(false))
+        .on_dispatch_error(dispatch_error)
+        .after(after)
+        .help(&HELP)
+        .group(&MUSIC_GROUP)
+        .group(&SETTINGS_GROUP)
+}
+
+#[hook]
+async fn guild_prefix(ctx: &Context, msg: &Message) -> Option<String> {
+    let state = AppState::from_context(ctx).await;
+    Some(match msg.guild_id {
+        None => state.config.prefix.clone(),
+        Some(guild_id) => state.prefix(guild_id)
+    })
+}
+
+#[hook]
+async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, command_name: &str) {
+    let reply_text = match error {
+        DispatchError::NotEnoughArguments { .. } | DispatchError::TooManyArguments { .. } => {
+            let prefix = guild_prefix(ctx, msg).await.unwrap_or_default();
+            format!("❌ Wrong arguments, see `{}help {}`", prefix, command_name)
+        }
+        DispatchError::LackingPermissions(_) => String::from("❌ You don't have permission to use that"),
+        _ => return
+    };
+    reply(ctx, msg, reply_text).await;
+}
+
+/// Commands return [AyakaError]s through [CommandResult] so they are reported the same way everywhere
+#[hook]
+async fn after(ctx: &Context, msg: &Message, command_name: &str, result: CommandResult) {
+    let err = match result {
+        Ok(_) => return,
+        Err(err) => err
+    };
+    error!("Command {} failed in {:?}: {}", command_name, msg.guild_id, err);
+    let reply_text = match err.downcast_ref::<AyakaError>() {
+        Some(err) => err.user_message(),
+        None => String::from("❌ Something went wrong")
+    };
+    reply(ctx, msg, reply_text).await;
+}
+
+/// Replies in music channels are deleted after a few seconds, like a failed request's
+async fn reply(ctx: &Context, msg: &Message, content: String) {
+    let state = AppState::from_context(ctx).await;
+    let channel_id = msg.channel_id;
+    if state.is_music_channel(channel_id) {
+        send_temporary_reply(&state, channel_id, content).await;
+    } else if let Err(err) = channel_id.say(ctx, content).await {
+        error!("Unable to reply in {}: {}", channel_id, err);
+    }
+}
+
+#[help]
+#[individual_command_tip = "Add a command name after help to see how to use it."]
+#[lacking_permissions = "Hide"]
+#[max_levenshtein_distance(2)]
+async fn help(ctx: &Context, msg: &Message, args: Args, help_options: &'static HelpOptions,
+              groups: &[&'static CommandGroup], owners: HashSet<UserId>) -> CommandResult {
+    // Music channels only hold the embed, the help goes away like any other reply there
+    if let Some(help) = help_commands::with_embeds(ctx, msg, args, help_options, groups, owners).await {
+        let state = AppState::from_context(ctx).await;
+        if state.is_music_channel(msg.channel_id) {
+            delete_later(&state, msg.channel_id, help.id);
+        }
+    }
+    Ok(())
+}
+
+/// The player of the music channel a command was sent in, otherwise whichever player holds the voice connection
+fn command_player(guild: &GuildManager, channel_id: ChannelId) -> PlayerId {
+    guild.player_in(channel_id).unwrap_or(guild.active)
+}
+
+/// Runs one of the embed's buttons on [command_player] and refreshes its embed
+async fn run_control(ctx: &Context, msg: &Message, control: &'static str) -> CommandResult {
+    let guild_id = msg.guild_id.ok_or(AyakaError::NotInGuild)?;
+    let state = AppState::from_context(ctx).await;
+    let (job_state, channel_id) = (state.clone(), msg.channel_id);
+    state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let player = command_player(guild, channel_id);
+        if let Some((music, embed)) = guild.player_mut(player) {
+            let (music_state, action) = apply_control(music, &job_state, control, None).await;
+            if let Some(embed) = embed {
+                embed.update_message(&job_state, music_state, action).await;
+            }
+        }
+    })).await.ok_or(AyakaError::GuildUnavailable)?;
+    msg.react(ctx, '✅').await.ok();
+    Ok(())
+}
+
+#[command]
+#[aliases(p)]
+#[description = "Queue a song by name or link, I join your voice channel unless this channel has its own"]
+#[usage = "<song name or link>"]
+#[example = "never gonna give you up"]
+#[min_args(1)]
+async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.ok_or(AyakaError::NotInGuild)?;
+    let state = AppState::from_context(ctx).await;
+    let (job_state, channel_id, author) = (state.clone(), msg.channel_id, msg.author.id);
+    let source = resolve_request(&state, args.rest().to_string()).await?;
+    state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let player = guild.player_in(channel_id).unwrap_or(PlayerId::Default);
+        queue_and_play(guild, player, &job_state, author, source).await
+    })).await.ok_or(AyakaError::GuildUnavailable)??;
+    msg.react(ctx, '✅').await.ok();
+    Ok(())
+}
+
+#[command]
+#[aliases(next, s)]
+#[description = "Skip to the next song"]
+async fn skip(ctx: &Context, msg: &Message) -> CommandResult {
+    run_control(ctx, msg, "next").await
+}
+
+#[command]
+#[aliases(prev, back)]
+#[description = "Go back to the previous song"]
+async fn previous(ctx: &Context, msg: &Message) -> CommandResult {
+    run_control(ctx, msg, "prev").await
+}
+
+#[command]
+#[description = "Stop playing and clear the queue"]
+async fn stop(ctx: &Context, msg: &Message) -> CommandResult {
+    run_control(ctx, msg, "stop").await
+}
+
+#[command("loop")]
+#[description = "Turn looping the queue on or off"]
+async fn toggle_loop(ctx: &Context, msg: &Message) -> CommandResult {
+    run_control(ctx, msg, "loop").await
+}
+
+#[command]
+#[description = "Turn shuffling on or off"]
+async fn shuffle(ctx: &Context, msg: &Message) -> CommandResult {
+    run_control(ctx, msg, "shuffle").await
+}
+
+#[command]
+#[aliases(q)]
+#[description = "List the upcoming songs"]
+async fn queue(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.ok_or(AyakaError::NotInGuild)?;
+    let state = AppState::from_context(ctx).await;
+    let channel_id = msg.channel_id;
+    let titles = state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let player = command_player(guild, channel_id);
+        guild.player_mut(player)
+            .map(|(music, _)| music.get_items_in_queue().into_iter().map(|item| item.title).collect::<Vec<String>>())
+            .unwrap_or_default()
+    })).await.ok_or(AyakaError::GuildUnavailable)?;
+
+    let reply_text = if titles.is_empty() {
+        String::from("The queue is empty")
+    } else {
+        let mut lines = titles.iter().take(QUEUE_PAGE_SIZE).enumerate()
+            .map(|(i, title)| format!("`{}.` {}", i + 1, title))
+            .collect::<Vec<String>>();
+        if titles.len() > QUEUE_PAGE_SIZE {
+            lines.push(format!("…and {} more", titles.len() - QUEUE_PAGE_SIZE));
+        }
+        lines.join("\n")
+    };
+    reply(ctx, msg, reply_text).await;
+    Ok(())
+}
+
+#[command]
+#[description = "Play a song from the queue right away, numbered as in the queue command"]
+#[usage = "<position>"]
+#[example = "3"]
+#[num_args(1)]
+async fn jump(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.ok_or(AyakaError::NotInGuild)?;
+    let position = match args.single::<usize>() {
+        Ok(position) if position > 0 => position,
+        _ => {
+            reply(ctx, msg, String::from("❌ The position has to be a number from the queue")).await;
+            return Ok(());
+        }
+    };
+
+    let state = AppState::from_context(ctx).await;
+    let (job_state, channel_id) = (state.clone(), msg.channel_id);
+    let jumped = state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let player = command_player(guild, channel_id);
+        let (music, embed) = match guild.player_mut(player) {
+            None => return false,
+            Some(player) => player
+        };
+        let index = match music.get_items_in_queue().get(position - 1) {
+            None => return false,
+            Some(item) => item.index
+        };
+        let (music_state, action) = apply_control(music, &job_state, "queue_select", Some(index)).await;
+        if let Some(embed) = embed {
+            embed.update_message(&job_state, music_state, action).await;
+        }
+        true
+    })).await.ok_or(AyakaError::GuildUnavailable)?;
+
+    if jumped {
+        msg.react(ctx, '✅').await.ok();
+    } else {
+        reply(ctx, msg, format!("❌ There is no song #{} in the queue", position)).await;
+    }
+    Ok(())
+}
+
+#[command]
+#[description = "Show or change the prefix for text commands, `reset` goes back to the default"]
+#[usage = "[new prefix | reset]"]
+#[example = "!"]
+#[required_permissions("MANAGE_GUILD")]
+#[max_args(1)]
+async fn prefix(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.ok_or(AyakaError::NotInGuild)?;
+    let state = AppState::from_context(ctx).await;
+    let new_prefix = match args.current() {
+        None => {
+            reply(ctx, msg, format!("The prefix is `{}`", state.prefix(guild_id))).await;
+            return Ok(());
+        }
+        Some("reset") => None,
+        Some(prefix) if prefix.chars().count() > MAX_PREFIX_LEN => {
+            reply(ctx, msg, format!("❌ The prefix can be at most {} characters", MAX_PREFIX_LEN)).await;
+            return Ok(());
+        }
+        Some(prefix) => Some(prefix.to_string())
+    };
+
+    let job_state = state.clone();
+    state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        guild.set_prefix(&job_state, new_prefix);
+    })).await.ok_or(AyakaError::GuildUnavailable)?;
+    reply(ctx, msg, format!("The prefix is now `{}`", state.prefix(guild_id))).await;
+    Ok(())
+}
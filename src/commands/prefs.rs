@@ -0,0 +1,162 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue};
+use crate::commands::interaction_msg_response;
+use crate::error::AyakaError;
+use crate::member::MemberPreferences;
+use crate::state::AppState;
+
+pub const PREFS_CMD_NAME: &str = "prefs";
+pub const PREFS_CMD_DESC: &str = "Your own playback preferences in this server";
+
+const MIN_VOLUME: i64 = 1;
+const MAX_VOLUME: i64 = 200;
+/// Blocked links per member, the oldest is dropped to make room
+const MAX_BLOCKED: usize = 100;
+/// Blocked links listed by /prefs show, keeps the message under discord's length limit
+const SHOWN_BLOCKED: usize = 15;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(PREFS_CMD_NAME).description(PREFS_CMD_DESC)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("volume")
+            .description("Volume of the tracks you request, leave it empty to reset")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("percent")
+                .description("100 is unchanged")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(MIN_VOLUME)
+                .max_int_value(MAX_VOLUME)
+                .required(false)))
+        .create_option(|option| option
+            .name("dm")
+            .description("Get a DM when a track you requested starts")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("enabled")
+                .description("Send the DMs")
+                .kind(CommandOptionType::Boolean)
+                .required(true)))
+        .create_option(|option| option
+            .name("block")
+            .description("Never replay a track on its own while you are listening")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("link")
+                .description("Link of the track, the one playing by default")
+                .kind(CommandOptionType::String)
+                .required(false)))
+        .create_option(|option| option
+            .name("unblock")
+            .description("Allow a blocked track to be replayed again")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("link")
+                .description("Link of the track")
+                .kind(CommandOptionType::String)
+                .required(true)))
+        .create_option(|option| option
+            .name("show")
+            .description("Show your preferences")
+            .kind(CommandOptionType::SubCommand))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let subcommand = match interaction.data.options.first() {
+        None => return,
+        Some(subcommand) => subcommand.clone()
+    };
+
+    let state = AppState::from_context(&ctx).await;
+    let user_id = interaction.user.id;
+    let response = state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        match subcommand.name.as_str() {
+            "volume" => {
+                let volume = match sub_option(&subcommand, "percent") {
+                    Some(CommandDataOptionValue::Integer(percent)) => Some(percent.clamp(MIN_VOLUME, MAX_VOLUME) as u32),
+                    _ => None
+                };
+                guild.member.update_preferences(user_id, |preferences| preferences.volume = volume);
+                guild.mark_dirty();
+                match volume {
+                    None => String::from("Your tracks play at normal volume again"),
+                    Some(volume) => format!("Your tracks play at {}% volume from the next one on", volume)
+                }
+            }
+            "dm" => {
+                let enabled = matches!(sub_option(&subcommand, "enabled"), Some(CommandDataOptionValue::Boolean(true)));
+                guild.member.update_preferences(user_id, |preferences| preferences.dm_now_playing = enabled);
+                guild.mark_dirty();
+                if enabled { String::from("You'll get a DM when your tracks start") } else { String::from("No more DMs about your tracks") }
+            }
+            "block" => {
+                let link = match sub_option(&subcommand, "link") {
+                    Some(CommandDataOptionValue::String(link)) => Some(link.trim().to_string()),
+                    _ => guild.player_mut(guild.active).and_then(|(music, _)| music.current_url())
+                };
+                let link = match link {
+                    None => return String::from("❌ Nothing is playing, give the link to block"),
+                    Some(link) => link
+                };
+                guild.member.update_preferences(user_id, |preferences| {
+                    if preferences.blocked.contains(&link) { return; }
+                    if preferences.blocked.len() >= MAX_BLOCKED {
+                        preferences.blocked.remove(0);
+                    }
+                    preferences.blocked.push(link.clone());
+                });
+                guild.mark_dirty();
+                format!("<{}> won't be replayed while you're listening", link)
+            }
+            "unblock" => {
+                let link = match sub_option(&subcommand, "link") {
+                    Some(CommandDataOptionValue::String(link)) => link.trim().to_string(),
+                    _ => return String::from("❌ Give the link to unblock")
+                };
+                let mut found = false;
+                guild.member.update_preferences(user_id, |preferences| {
+                    let before = preferences.blocked.len();
+                    preferences.blocked.retain(|blocked| *blocked != link);
+                    found = preferences.blocked.len() != before;
+                });
+                if !found { return format!("❌ <{}> isn't blocked", link); }
+                guild.mark_dirty();
+                format!("<{}> can be replayed again", link)
+            }
+            _ => describe(&guild.member.preferences(user_id))
+        }
+    })).await.unwrap_or_else(|| AyakaError::GuildUnavailable.user_message());
+
+    interaction.create_interaction_response(&ctx.http, |i| {
+        *i = interaction_msg_response(&response, true); i
+    }).await.ok();
+}
+
+fn sub_option<'a>(subcommand: &'a CommandDataOption, name: &str) -> Option<&'a CommandDataOptionValue> {
+    subcommand.options.iter().find(|option| option.name == name).and_then(|option| option.resolved.as_ref())
+}
+
+fn describe(preferences: &MemberPreferences) -> String {
+    let volume = match preferences.volume {
+        None => String::from("normal"),
+        Some(volume) => format!("{}%", volume)
+    };
+    let blocked = if preferences.blocked.is_empty() {
+        String::from("nothing")
+    } else {
+        let mut shown = preferences.blocked.iter().rev().take(SHOWN_BLOCKED).map(|link| format!("\n- <{}>", link)).collect::<String>();
+        if preferences.blocked.len() > SHOWN_BLOCKED {
+            shown.push_str(&format!("\n…and {} more", preferences.blocked.len() - SHOWN_BLOCKED));
+        }
+        shown
+    };
+    format!("**Volume:** {}\n**DM when your tracks start:** {}\n**Blocked:** {}",
+        volume, if preferences.dm_now_playing { "yes" } else { "no" }, blocked)
+}
@@ -0,0 +1,97 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::command::CommandType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, ResolvedTarget};
+use serenity::model::channel::Message;
+use tracing::error;
+use crate::commands::{defer_ephemeral, edit_response};
+use crate::error::AyakaError;
+use crate::guild::player::PlayerId;
+use crate::interaction::{queue_and_play, resolve_request};
+use crate::state::AppState;
+
+/// Context menu commands are shown by name, so it reads as an action
+pub const QUEUE_LINK_CMD_NAME: &str = "Queue this link";
+
+/// Links queued from one message at most, the rest are ignored
+const MAX_LINKS: usize = 10;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(QUEUE_LINK_CMD_NAME)
+        .kind(CommandType::Message)
+        .dm_permission(false)
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let links = match interaction.data.target() {
+        Some(ResolvedTarget::Message(message)) => find_links(&message),
+        _ => return
+    };
+    defer_ephemeral(&ctx, &interaction).await;
+    if links.is_empty() {
+        edit_response(&ctx, &interaction, "❌ That message has no links or audio attachments").await;
+        return;
+    }
+
+    let state = AppState::from_context(&ctx).await;
+    let (job_state, channel_id, author, total) = (state.clone(), interaction.channel_id, interaction.user.id, links.len());
+    // Looked up before entering the guild's actor, a slow link shouldn't hold up the guild
+    let (mut sources, mut lookup_failure) = (Vec::with_capacity(total), None);
+    for link in links {
+        match resolve_request(&state, link.clone()).await {
+            Ok(source) => sources.push(source),
+            Err(err) => {
+                error!("Unable to look up {} in {}: {}", link, guild_id, err);
+                lookup_failure.get_or_insert(err);
+            }
+        }
+    }
+    // Queued one after another in a single job so nothing else lands in between
+    let (queued, failure) = state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let player = guild.player_in(channel_id).unwrap_or(PlayerId::Default);
+        let (mut queued, mut failure) = (0, None);
+        for source in sources {
+            match queue_and_play(guild, player, &job_state, author, source).await {
+                Ok(_) => queued += 1,
+                Err(err) => {
+                    error!("Unable to queue in {}: {}", guild.id, err);
+                    // Without a voice channel none of the others will work either
+                    let stop = matches!(err, AyakaError::NotInVoice | AyakaError::VoiceBusy(_) | AyakaError::VoiceJoin(_));
+                    failure.get_or_insert(err);
+                    if stop { break; }
+                }
+            }
+        }
+        (queued, failure)
+    })).await.unwrap_or((0, Some(AyakaError::GuildUnavailable)));
+    let failure = failure.or(lookup_failure);
+
+    let response = match failure {
+        None if total == 1 => String::from("Queued 1 link"),
+        None => format!("Queued {} links", total),
+        Some(err) if queued == 0 => err.user_message(),
+        Some(err) => format!("Queued {} of {} links, {}", queued, total, err.user_message())
+    };
+    edit_response(&ctx, &interaction, &response).await;
+}
+
+/// Links in the message text first, then attachments ytdl can play directly
+fn find_links(message: &Message) -> Vec<String> {
+    let mut links = message.content.split_whitespace()
+        // Discord lets people wrap links in <> to hide the preview
+        .map(|word| word.trim_start_matches('<').trim_end_matches(|c: char| matches!(c, '>' | ')' | ',' | '.')))
+        .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .map(String::from)
+        .collect::<Vec<String>>();
+    links.extend(message.attachments.iter()
+        .filter(|attachment| attachment.content_type.as_ref()
+            .is_some_and(|kind| kind.starts_with("audio/") || kind.starts_with("video/")))
+        .map(|attachment| attachment.url.clone()));
+    links.dedup();
+    links.truncate(MAX_LINKS);
+    links
+}
@@ -0,0 +1,66 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOptionValue};
+use tracing::error;
+use crate::commands::{defer_ephemeral, edit_response};
+use crate::error::AyakaError;
+use crate::guild::player::PlayerId;
+use crate::interaction::{queue_and_play, resolve_request};
+use crate::state::AppState;
+
+pub const REPLAY_CMD_NAME: &str = "replay";
+pub const REPLAY_CMD_DESC: &str = "Queue a track from /history again";
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(REPLAY_CMD_NAME).description(REPLAY_CMD_DESC)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("number")
+            .description("Number of the track in /history")
+            .kind(CommandOptionType::Integer)
+            .min_int_value(1)
+            .required(true))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let number = match interaction.data.options.first().and_then(|option| option.resolved.as_ref()) {
+        Some(CommandDataOptionValue::Integer(number)) if *number > 0 => *number as u64,
+        _ => return
+    };
+    // Queueing searches the link again, which can take longer than discord waits for a response
+    defer_ephemeral(&ctx, &interaction).await;
+
+    let state = AppState::from_context(&ctx).await;
+    let (job_state, channel_id, author) = (state.clone(), interaction.channel_id, interaction.user.id);
+    let handle = state.guild_or_default(guild_id);
+    let play = handle.history.read().get(number).cloned();
+    // Plays without a link were found by searching, searching the title finds them again
+    let result = match play.and_then(|play| Some((play.url.clone().or_else(|| play.title.clone())?, play.title))) {
+        None => None,
+        Some((search, title)) => {
+            let title = title.unwrap_or_else(|| search.clone());
+            Some(match resolve_request(&state, search).await {
+                Err(err) => Err(err),
+                Ok(source) => handle.call(move |guild| Box::pin(async move {
+                    let player = guild.player_in(channel_id).unwrap_or(PlayerId::Default);
+                    queue_and_play(guild, player, &job_state, author, source).await.map(|_| title)
+                })).await.unwrap_or(Err(AyakaError::GuildUnavailable))
+            })
+        }
+    };
+
+    let response = match result {
+        None => format!("❌ There is no play #{} in /history", number),
+        Some(Ok(title)) => format!("Queued `{}` again", title),
+        Some(Err(err)) => {
+            error!("/replay failed in {}: {}", guild_id, err);
+            err.user_message()
+        }
+    };
+    edit_response(&ctx, &interaction, &response).await;
+}
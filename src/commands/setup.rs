@@ -1,34 +1,193 @@
-use serenity::builder::{CreateApplicationCommand};
+use std::sync::Arc;
+
+use serenity::builder::CreateApplicationCommand;
 use serenity::client::Context;
-use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
-use crate::commands::interaction_msg_response;
-use crate::guild::{GUILD_REGISTRY, GuildManager};
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue};
+use serenity::model::channel::ChannelType;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::permissions::Permissions;
+use tracing::error;
+use crate::commands::{defer_ephemeral, edit_response};
+use crate::error::{AyakaError, AyakaResult};
+use crate::guild::player::PlayerId;
+use crate::interaction::channel::{lock_music_channel, unlock_music_channel};
+use crate::state::AppState;
 
 pub const SETUP_CMD_NAME: &str = "setup";
-pub const SETUP_CMD_DESC: &str = "Use in an empty channel to designate it as this guild's music channel";
+pub const SETUP_CMD_DESC: &str = "Choose, create or remove this guild's music channel";
+
+/// Discord already checks the options it was registered with, this only shows if registration is out of date
+const UNKNOWN_OPTIONS: &str = "❌ Unknown options, the command may still be updating, try again in a minute";
 
 pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
     command.name(SETUP_CMD_NAME).description(SETUP_CMD_DESC)
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("here")
+            .description("Use this channel, it has to be empty")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("voice")
+                .description("Give this channel its own queue that always plays in this voice channel")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Voice, ChannelType::Stage])
+                .required(false)))
+        .create_option(|option| option
+            .name("create")
+            .description("Create a new music channel")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("name")
+                .description("Name of the new channel")
+                .kind(CommandOptionType::String)
+                .required(true))
+            .create_sub_option(|sub| sub
+                .name("category")
+                .description("Category to create it in")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Category])
+                .required(false))
+            .create_sub_option(|sub| sub
+                .name("voice")
+                .description("Give this channel its own queue that always plays in this voice channel")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Voice, ChannelType::Stage])
+                .required(false)))
+        .create_option(|option| option
+            .name("channel")
+            .description("Use an existing channel, it has to be empty")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("channel")
+                .description("Channel to use")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Text])
+                .required(true))
+            .create_sub_option(|sub| sub
+                .name("voice")
+                .description("Give this channel its own queue that always plays in this voice channel")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Voice, ChannelType::Stage])
+                .required(false)))
+        .create_option(|option| option
+            .name("remove")
+            .description("Stop using a music channel")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("channel")
+                .description("Music channel to remove, defaults to this one or the main one")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Text])
+                .required(false)))
 }
 
 pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
-    let acquire_lock = GUILD_REGISTRY.lock().await;
-
     let guild_id = match interaction.guild_id {
         None => return,
         Some(guild_id) => guild_id
     };
+    // Clearing the channel and posting the embed can take longer than discord waits for a response
+    defer_ephemeral(&ctx, &interaction).await;
+
+    let state = AppState::from_context(&ctx).await;
+    let subcommand = match interaction.data.options.first() {
+        None => return edit_response(&ctx, &interaction, UNKNOWN_OPTIONS).await,
+        Some(subcommand) => subcommand
+    };
+    let voice = option_channel(subcommand, "voice");
+    let result = match subcommand.name.as_str() {
+        "here" => use_channel(&ctx, &state, guild_id, interaction.channel_id, voice).await,
+        "channel" => match option_channel(subcommand, "channel") {
+            None => Ok(String::from(UNKNOWN_OPTIONS)),
+            Some(channel_id) => use_channel(&ctx, &state, guild_id, channel_id, voice).await
+        },
+        "create" => create_channel(&ctx, &state, guild_id, subcommand, voice).await,
+        "remove" => {
+            let channel_id = option_channel(subcommand, "channel").unwrap_or(interaction.channel_id);
+            remove_channel(&state, guild_id, channel_id).await
+        }
+        _ => Ok(String::from(UNKNOWN_OPTIONS))
+    };
+
+    let response = match result {
+        Ok(response) => response,
+        Err(err) => {
+            error!("/setup {} failed in {}: {}", subcommand.name, guild_id, err);
+            err.user_message()
+        }
+    };
+    edit_response(&ctx, &interaction, &response).await;
+}
+
+fn option_channel(subcommand: &CommandDataOption, name: &str) -> Option<ChannelId> {
+    subcommand.options.iter().find(|option| option.name == name).and_then(|option| match &option.resolved {
+        Some(CommandDataOptionValue::Channel(channel)) => Some(channel.id),
+        _ => None
+    })
+}
+
+/// With `voice` the channel gets its own queue bound to that voice channel, otherwise it becomes the main music channel
+async fn use_channel(ctx: &Context, state: &Arc<AppState>, guild_id: GuildId, channel_id: ChannelId, voice: Option<ChannelId>) -> AyakaResult<String> {
+    let (job_ctx, job_state) = (ctx.clone(), state.clone());
+    state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        match voice {
+            None => guild.new_channel(&job_ctx, &job_state, channel_id).await,
+            Some(voice) => guild.new_bound_channel(&job_ctx, &job_state, channel_id, voice).await
+        }
+    })).await.ok_or(AyakaError::GuildUnavailable)??;
+
+    let response = match voice {
+        None => format!("Music channel set to <#{}>", channel_id),
+        Some(voice) => format!("<#{}> now has its own queue playing in <#{}>", channel_id, voice)
+    };
+    if let Err(err) = lock_music_channel(state, guild_id, channel_id).await {
+        error!("Unable to lock music channel {}: {}", channel_id, err);
+        return Ok(format!("{}, but I couldn't set its permissions and slow-mode", response));
+    }
+    Ok(response)
+}
 
-    let manager = acquire_lock.get(&guild_id);
+async fn create_channel(ctx: &Context, state: &Arc<AppState>, guild_id: GuildId, subcommand: &CommandDataOption, voice: Option<ChannelId>) -> AyakaResult<String> {
+    let name = subcommand.options.iter()
+        .find(|option| option.name == "name")
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+        .unwrap_or("ayaka-music")
+        .to_string();
+    let category = option_channel(subcommand, "category");
 
-    let manager = match manager {
-        Some(manager) => manager.clone(),
-        None => {
-            GuildManager::new(guild_id).register_already_locked(acquire_lock).await
+    let channel = guild_id.create_channel(&state.http, |channel| {
+        channel.name(name).kind(ChannelType::Text).topic("Type a song name or link to queue it");
+        if let Some(category) = category {
+            channel.category(category);
+        }
+        channel
+    }).await?;
+    use_channel(ctx, state, guild_id, channel.id, voice).await
+}
+
+/// Removes the player using `channel_id`, or the main music channel if `channel_id` isn't one
+async fn remove_channel(state: &Arc<AppState>, guild_id: GuildId, channel_id: ChannelId) -> AyakaResult<String> {
+    let removed = match state.guild(guild_id) {
+        None => None,
+        Some(handle) => {
+            let job_state = state.clone();
+            handle.call(move |guild| Box::pin(async move {
+                let player = guild.player_in(channel_id).unwrap_or(PlayerId::Default);
+                guild.remove_player(&job_state, player).await
+            })).await.ok_or(AyakaError::GuildUnavailable)?
         }
     };
-    manager.lock().await.new_channel(&ctx, interaction.channel_id).await;
-    interaction.create_interaction_response(&ctx.http, |i| {
-        *i = interaction_msg_response("Successfully setup channel", true); i
-    }).await.ok();
-}
\ No newline at end of file
+    let (channel_id, message_id) = match removed {
+        None => return Ok(String::from("There is no music channel to remove")),
+        Some(removed) => removed
+    };
+
+    if let Some(message_id) = message_id {
+        state.messenger.delete(channel_id, message_id).await.ok();
+    }
+    unlock_music_channel(state, guild_id, channel_id).await;
+    Ok(format!("<#{}> is no longer the music channel", channel_id))
+}
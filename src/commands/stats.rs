@@ -0,0 +1,142 @@
+use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOptionValue};
+use serenity::model::id::UserId;
+use serenity::model::user::User;
+use tracing::error;
+use crate::commands::truncate;
+use crate::error::AyakaError;
+use crate::member::{MemberManager, MemberStats};
+use crate::state::AppState;
+
+pub const STATS_CMD_NAME: &str = "stats";
+pub const STATS_CMD_DESC: &str = "Listening statistics of a member or the whole server";
+
+/// Entries shown per list
+const LEADERBOARD_SIZE: usize = 10;
+const TOP_SIZE: usize = 5;
+/// Keeps a full list under discord's 1024 character limit for embed fields
+const MAX_NAME_LEN: usize = 60;
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(STATS_CMD_NAME).description(STATS_CMD_DESC)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("me")
+            .description("Your own statistics")
+            .kind(CommandOptionType::SubCommand))
+        .create_option(|option| option
+            .name("user")
+            .description("Statistics of another member")
+            .kind(CommandOptionType::SubCommand)
+            .create_sub_option(|sub| sub
+                .name("user")
+                .description("Member to look up")
+                .kind(CommandOptionType::User)
+                .required(true)))
+        .create_option(|option| option
+            .name("server")
+            .description("Leaderboards for the whole server")
+            .kind(CommandOptionType::SubCommand))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+    let subcommand = match interaction.data.options.first() {
+        None => return,
+        Some(subcommand) => subcommand
+    };
+    let target = match subcommand.name.as_str() {
+        "me" => Some(interaction.user.clone()),
+        "user" => match subcommand.options.first().and_then(|option| option.resolved.as_ref()) {
+            Some(CommandDataOptionValue::User(user, _)) => Some(user.clone()),
+            _ => return
+        },
+        _ => None
+    };
+
+    let state = AppState::from_context(&ctx).await;
+    let mut embed = match state.guild(guild_id) {
+        None => Ok(empty_embed(target.as_ref())),
+        Some(handle) => handle.call(move |guild| Box::pin(async move {
+            match target {
+                None => server_embed(&guild.member),
+                Some(user) => {
+                    let rank = guild.member.leaderboard(|stats| stats.listening_secs, usize::MAX)
+                        .iter().position(|(user_id, _)| *user_id == user.id);
+                    match guild.member.stats(user.id) {
+                        None => empty_embed(Some(&user)),
+                        Some(stats) => member_embed(&user, stats, rank)
+                    }
+                }
+            }
+        })).await.ok_or(AyakaError::GuildUnavailable)
+    };
+    if let Ok(embed) = &mut embed {
+        embed.color(state.config.embed_color());
+    }
+
+    let result = interaction.create_interaction_response(&ctx.http, |response| response
+        .interaction_response_data(|data| match embed {
+            Ok(embed) => data.add_embed(embed),
+            Err(err) => data.ephemeral(true).content(err.user_message())
+        })).await;
+    if let Err(err) = result {
+        error!("Unable to respond to /stats: {}", err);
+    }
+}
+
+fn member_embed(user: &User, stats: &MemberStats, rank: Option<usize>) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed.title(format!("{}'s listening stats", user.name))
+        .field("Requested", format!("{} tracks", stats.tracks_requested), true)
+        .field("Listened", format_duration(stats.listening_secs), true);
+    if let Some(rank) = rank {
+        embed.field("Rank", format!("#{}", rank + 1), true);
+    }
+    embed.field("Top tracks", numbered(stats.top_tracks(TOP_SIZE).into_iter().map(|(title, count)| format!("{} ({})", truncate(title, MAX_NAME_LEN), count))), false)
+        .field("Top artists", numbered(stats.top_artists(TOP_SIZE).into_iter().map(|(artist, count)| format!("{} ({})", truncate(artist, MAX_NAME_LEN), count))), false);
+    embed
+}
+
+fn server_embed(members: &MemberManager) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed.title("Server listening stats")
+        .field("Top listeners", numbered(members.leaderboard(|stats| stats.listening_secs, LEADERBOARD_SIZE).into_iter()
+            .map(|(user_id, secs)| format!("{} {}", mention(user_id), format_duration(secs)))), true)
+        .field("Top requesters", numbered(members.leaderboard(|stats| stats.tracks_requested, LEADERBOARD_SIZE).into_iter()
+            .map(|(user_id, count)| format!("{} {}", mention(user_id), count))), true)
+        .field("Top tracks", numbered(members.guild_top_tracks(LEADERBOARD_SIZE).into_iter()
+            .map(|(title, count)| format!("{} ({})", truncate(title, MAX_NAME_LEN), count))), false)
+        .field("Top artists", numbered(members.guild_top_artists(LEADERBOARD_SIZE).into_iter()
+            .map(|(artist, count)| format!("{} ({})", truncate(artist, MAX_NAME_LEN), count))), false);
+    embed
+}
+
+fn empty_embed(user: Option<&User>) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed.description(match user {
+            None => String::from("Nobody has listened to anything yet"),
+            Some(user) => format!("{} hasn't listened to anything yet", mention(user.id))
+        });
+    embed
+}
+
+fn mention(user_id: UserId) -> String {
+    format!("<@{}>", user_id)
+}
+
+/// One entry per line, embed fields can't be empty so a dash stands in for no entries
+fn numbered(entries: impl Iterator<Item = String>) -> String {
+    let lines = entries.enumerate().map(|(i, entry)| format!("`{}.` {}", i + 1, entry)).collect::<Vec<String>>();
+    if lines.is_empty() { String::from("-") } else { lines.join("\n") }
+}
+
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+    if hours > 0 { format!("{}h {}m", hours, minutes) } else { format!("{}m", minutes) }
+}
@@ -0,0 +1,76 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOptionValue};
+use serenity::model::channel::ChannelType;
+use serenity::model::permissions::Permissions;
+use tracing::error;
+use crate::commands::{defer_ephemeral, edit_response};
+use crate::error::AyakaError;
+use crate::state::AppState;
+
+pub const STAY_CMD_NAME: &str = "stay";
+pub const STAY_CMD_DESC: &str = "Keep the bot connected to a voice channel 24/7, leave the channel empty to disable";
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name(STAY_CMD_NAME).description(STAY_CMD_DESC)
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
+        .dm_permission(false)
+        .create_option(|option| option
+            .name("channel")
+            .description("Voice channel to stay in")
+            .kind(CommandOptionType::Channel)
+            .channel_types(&[ChannelType::Voice, ChannelType::Stage])
+            .required(false))
+        .create_option(|option| option
+            .name("idle")
+            .description("Playlist or radio url to play while the queue is empty")
+            .kind(CommandOptionType::String)
+            .required(false))
+}
+
+pub async fn execute(ctx: Context, interaction: ApplicationCommandInteraction) {
+    let guild_id = match interaction.guild_id {
+        None => return,
+        Some(guild_id) => guild_id
+    };
+
+    let mut channel_id = None;
+    let mut idle_source = None;
+    for option in &interaction.data.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("channel", Some(CommandDataOptionValue::Channel(channel))) => channel_id = Some(channel.id),
+            ("idle", Some(CommandDataOptionValue::String(url))) => idle_source = Some(url.clone()),
+            _ => {}
+        }
+    }
+
+    // Joining and resolving the idle source can take longer than discord waits for a response
+    defer_ephemeral(&ctx, &interaction).await;
+    let state = AppState::from_context(&ctx).await;
+    let response = state.guild_or_default(guild_id).call(move |guild| Box::pin(async move {
+        let was_staying = guild.music.stay_connected.is_some();
+        guild.music.stay_connected = channel_id;
+        guild.music.idle_source = idle_source;
+        guild.mark_dirty();
+
+        match channel_id {
+            None => {
+                // With the queue done only the idle source is left playing, and nothing would ever stop it
+                if was_staying && !guild.music.is_playing && let Err(err) = guild.music.leave(&state).await {
+                    error!("Unable to leave the 24/7 channel: {}", err);
+                }
+                "24/7 mode disabled".to_string()
+            }
+            Some(channel_id) => match guild.resume_stay(&state).await {
+                Err(err) => {
+                    error!("Unable to join 24/7 channel {}: {}", channel_id, err);
+                    err.user_message()
+                }
+                Ok(_) => format!("24/7 mode enabled in <#{}>", channel_id)
+            }
+        }
+    })).await.unwrap_or_else(|| AyakaError::GuildUnavailable.user_message());
+
+    edit_response(&ctx, &interaction, &response).await;
+}
@@ -0,0 +1,292 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serenity::model::gateway::GatewayIntents;
+use serenity::utils::Color;
+
+use crate::interaction::menu_defaults::{MUSIC_EMBED_COLOR, MUSIC_EMBED_IMAGE};
+use crate::storage::StorageBackend;
+
+pub const DEFAULT_CONFIG_FILE: &str = "ayaka.toml";
+const CONFIG_PATH_VAR: &str = "AYAKA_CONFIG";
+/// Read when `AYAKA_TOKEN` isn't set, kept so existing deployments don't need a new variable
+const LEGACY_TOKEN_VAR: &str = "bot_token";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub token: String,
+    pub prefix: String,
+    /// Names of gateway intents to request on top of the ones the enabled features need
+    pub intents: Vec<String>,
+    /// How often in seconds to check for unsaved guild changes
+    pub save_interval: u64,
+    /// Seconds to wait after being removed from a guild before its saved state is deleted
+    pub leave_grace: u64,
+    pub storage: StorageConfig,
+    pub embed: EmbedConfig,
+    pub features: FeatureConfig
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub json_path: PathBuf,
+    pub backup_dir: PathBuf,
+    pub sqlite_path: PathBuf
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbedConfig {
+    pub image: String,
+    /// Hex colour such as `#786BC7`
+    pub color: String
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub music_channel: bool,
+    pub prefix_commands: bool,
+    pub stay_connected: bool,
+    pub member_tracking: bool,
+    /// Create an `#ayaka-music` channel with the music embed when joining a guild
+    pub auto_music_channel: bool
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            token: String::new(),
+            prefix: String::from(if cfg!(debug_assertions) { "e!" } else { "~" }),
+            intents: vec![],
+            save_interval: 5,
+            leave_grace: 600,
+            storage: StorageConfig::default(),
+            embed: EmbedConfig::default(),
+            features: FeatureConfig::default()
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Json,
+            json_path: PathBuf::from("guild_cache.json"),
+            backup_dir: PathBuf::from("guild_backups"),
+            sqlite_path: PathBuf::from("ayaka.sqlite3")
+        }
+    }
+}
+
+impl Default for EmbedConfig {
+    fn default() -> Self {
+        EmbedConfig {
+            image: MUSIC_EMBED_IMAGE.to_string(),
+            color: format!("#{}", MUSIC_EMBED_COLOR.hex())
+        }
+    }
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        FeatureConfig {
+            music_channel: true,
+            prefix_commands: true,
+            stay_connected: true,
+            member_tracking: false,
+            auto_music_channel: false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(Vec<String>)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Unable to read config file {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "Invalid config file {}: {}", path.display(), err),
+            ConfigError::Invalid(problems) => {
+                writeln!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the config file (missing is fine), applies environment overrides and validates the result
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = env::var(CONFIG_PATH_VAR).map(PathBuf::from).unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_FILE));
+        let mut config = match fs::read_to_string(&path) {
+            Ok(input) => toml::from_str(&input).map_err(|err| ConfigError::Parse(path.clone(), err))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Config::default(),
+            Err(err) => return Err(ConfigError::Read(path, err))
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if let Some(token) = env_var("AYAKA_TOKEN").or_else(|| env_var(LEGACY_TOKEN_VAR)) {
+            self.token = token;
+        }
+        if let Some(prefix) = env_var("AYAKA_PREFIX") { self.prefix = prefix; }
+        if let Some(intents) = env_var("AYAKA_INTENTS") {
+            self.intents = intents.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        }
+        if let Some(interval) = env_var("AYAKA_SAVE_INTERVAL") {
+            match interval.parse() {
+                Ok(interval) => self.save_interval = interval,
+                Err(_) => problems.push(format!("AYAKA_SAVE_INTERVAL must be a whole number of seconds, got \"{}\"", interval))
+            }
+        }
+        if let Some(grace) = env_var("AYAKA_LEAVE_GRACE") {
+            match grace.parse() {
+                Ok(grace) => self.leave_grace = grace,
+                Err(_) => problems.push(format!("AYAKA_LEAVE_GRACE must be a whole number of seconds, got \"{}\"", grace))
+            }
+        }
+        if let Some(backend) = env_var("AYAKA_STORAGE") {
+            match backend.to_lowercase().as_str() {
+                "json" => self.storage.backend = StorageBackend::Json,
+                "sqlite" => self.storage.backend = StorageBackend::Sqlite,
+                _ => problems.push(format!("AYAKA_STORAGE must be \"json\" or \"sqlite\", got \"{}\"", backend))
+            }
+        }
+        if let Some(path) = env_var("AYAKA_JSON_PATH") { self.storage.json_path = PathBuf::from(path); }
+        if let Some(path) = env_var("AYAKA_BACKUP_DIR") { self.storage.backup_dir = PathBuf::from(path); }
+        if let Some(path) = env_var("AYAKA_SQLITE_PATH") { self.storage.sqlite_path = PathBuf::from(path); }
+        if let Some(image) = env_var("AYAKA_EMBED_IMAGE") { self.embed.image = image; }
+        if let Some(color) = env_var("AYAKA_EMBED_COLOR") { self.embed.color = color; }
+
+        let features = [
+            ("AYAKA_FEATURE_MUSIC_CHANNEL", &mut self.features.music_channel),
+            ("AYAKA_FEATURE_PREFIX_COMMANDS", &mut self.features.prefix_commands),
+            ("AYAKA_FEATURE_STAY_CONNECTED", &mut self.features.stay_connected),
+            ("AYAKA_FEATURE_MEMBER_TRACKING", &mut self.features.member_tracking),
+            ("AYAKA_FEATURE_AUTO_MUSIC_CHANNEL", &mut self.features.auto_music_channel),
+        ];
+        for (var, feature) in features {
+            if let Some(value) = env_var(var) {
+                match value.to_lowercase().as_str() {
+                    "1" | "true" | "yes" | "on" => *feature = true,
+                    "0" | "false" | "no" | "off" => *feature = false,
+                    _ => problems.push(format!("{} must be true or false, got \"{}\"", var, value))
+                }
+            }
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigError::Invalid(problems)) }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.token.trim().is_empty() {
+            problems.push(String::from("No bot token, set `token` in the config file or the AYAKA_TOKEN environment variable"));
+        }
+        if self.prefix.is_empty() || self.prefix.chars().any(char::is_whitespace) {
+            problems.push(format!("`prefix` must be non-empty and contain no whitespace, got \"{}\"", self.prefix));
+        }
+        if self.save_interval == 0 {
+            problems.push(String::from("`save_interval` must be at least 1 second"));
+        }
+        if let Err(err) = self.gateway_intents() {
+            problems.push(err);
+        }
+        if !self.embed.image.starts_with("http://") && !self.embed.image.starts_with("https://") {
+            problems.push(format!("`embed.image` must be an http(s) url, got \"{}\"", self.embed.image));
+        }
+        if parse_color(&self.embed.color).is_none() {
+            problems.push(format!("`embed.color` must be a hex colour like \"#786BC7\", got \"{}\"", self.embed.color));
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigError::Invalid(problems)) }
+    }
+
+    /// Intents the enabled features need plus any extra ones from `intents`
+    pub fn gateway_intents(&self) -> Result<GatewayIntents, String> {
+        let mut intents = self.features.required_intents();
+        for name in &self.intents {
+            intents |= intent_from_name(name).ok_or_else(|| format!("Unknown gateway intent \"{}\" in `intents`", name))?;
+        }
+        Ok(intents)
+    }
+
+    pub fn embed_color(&self) -> Color {
+        parse_color(&self.embed.color).unwrap_or(MUSIC_EMBED_COLOR)
+    }
+}
+
+impl FeatureConfig {
+    pub fn required_intents(&self) -> GatewayIntents {
+        // Guild create events fill the cache that channel and voice state lookups rely on.
+        // Songbird and get_user_vc need voice states, the playback commands are registered whatever the features
+        let mut intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES;
+        if self.music_channel {
+            // Song requests are read from plain messages in the music channel
+            intents |= GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+        }
+        if self.prefix_commands {
+            intents |= GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+        }
+        intents
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    let hex = color.trim_start_matches('#');
+    if hex.len() != 6 { return None; }
+    u32::from_str_radix(hex, 16).ok().map(Color::new)
+}
+
+fn intent_from_name(name: &str) -> Option<GatewayIntents> {
+    Some(match name.to_uppercase().as_str() {
+        "ALL" => GatewayIntents::all(),
+        "NON_PRIVILEGED" => GatewayIntents::non_privileged(),
+        "GUILDS" => GatewayIntents::GUILDS,
+        "GUILD_MEMBERS" => GatewayIntents::GUILD_MEMBERS,
+        "GUILD_BANS" => GatewayIntents::GUILD_BANS,
+        "GUILD_EMOJIS_AND_STICKERS" => GatewayIntents::GUILD_EMOJIS_AND_STICKERS,
+        "GUILD_INTEGRATIONS" => GatewayIntents::GUILD_INTEGRATIONS,
+        "GUILD_WEBHOOKS" => GatewayIntents::GUILD_WEBHOOKS,
+        "GUILD_INVITES" => GatewayIntents::GUILD_INVITES,
+        "GUILD_VOICE_STATES" => GatewayIntents::GUILD_VOICE_STATES,
+        "GUILD_PRESENCES" => GatewayIntents::GUILD_PRESENCES,
+        "GUILD_MESSAGES" => GatewayIntents::GUILD_MESSAGES,
+        "GUILD_MESSAGE_REACTIONS" => GatewayIntents::GUILD_MESSAGE_REACTIONS,
+        "GUILD_MESSAGE_TYPING" => GatewayIntents::GUILD_MESSAGE_TYPING,
+        "DIRECT_MESSAGES" => GatewayIntents::DIRECT_MESSAGES,
+        "DIRECT_MESSAGE_REACTIONS" => GatewayIntents::DIRECT_MESSAGE_REACTIONS,
+        "DIRECT_MESSAGE_TYPING" => GatewayIntents::DIRECT_MESSAGE_TYPING,
+        "MESSAGE_CONTENT" => GatewayIntents::MESSAGE_CONTENT,
+        "GUILD_SCHEDULED_EVENTS" => GatewayIntents::GUILD_SCHEDULED_EVENTS,
+        _ => return None
+    })
+}
@@ -0,0 +1,134 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use serenity::model::id::ChannelId;
+use serenity::prelude::SerenityError;
+use songbird::error::JoinError;
+use songbird::input::error::Error as InputError;
+
+pub type AyakaResult<T> = Result<T, AyakaError>;
+
+#[derive(Debug)]
+pub enum AyakaError {
+    /// Used outside of a guild, or the guild isn't in the cache
+    NotInGuild,
+    /// The author has to be in a voice channel for the bot to know where to join
+    NotInVoice,
+    VoiceJoin(JoinError),
+    /// ytdl couldn't turn a url or search into something playable
+    Source(InputError),
+    /// /setup was used on a channel that already has other people's messages
+    ChannelNotEmpty,
+    /// The channel is already another player's music channel
+    ChannelInUse,
+    /// Another player in the guild holds the voice connection and is still playing
+    VoiceBusy(Option<ChannelId>),
+    /// The guild's actor stopped before it could handle the request
+    GuildUnavailable,
+    Io(io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    /// Persisted state exists but can't be recovered
+    Corrupt(String),
+    Discord(SerenityError)
+}
+
+impl AyakaError {
+    /// Short message meant for the music channel or an ephemeral reply, [Display] is meant for logs
+    pub fn user_message(&self) -> String {
+        match self {
+            AyakaError::NotInGuild => String::from("❌ This only works inside a server"),
+            AyakaError::NotInVoice => String::from("❌ Join a voice channel first"),
+            AyakaError::VoiceJoin(_) => String::from("❌ Unable to join your voice channel, check my permissions"),
+            AyakaError::Source(err) => source_message(err),
+            AyakaError::ChannelNotEmpty => String::from("❌ The music channel must be empty, try `/setup create` instead"),
+            AyakaError::ChannelInUse => String::from("❌ That channel is already used by another player"),
+            AyakaError::VoiceBusy(Some(channel_id)) =>
+                format!("❌ Already playing in <#{}>, I can only be in one voice channel per server", channel_id),
+            AyakaError::VoiceBusy(None) => String::from("❌ Already playing in another voice channel"),
+            AyakaError::GuildUnavailable => String::from("❌ Music is restarting, try again in a moment"),
+            AyakaError::Io(_) | AyakaError::Json(_) | AyakaError::Sqlite(_) | AyakaError::Corrupt(_) =>
+                String::from("❌ Unable to save settings, try again later"),
+            AyakaError::Discord(_) => String::from("❌ Discord rejected the request, check my permissions")
+        }
+    }
+}
+
+/// ytdl only reports why a video can't be played through its stderr, so it has to be matched on text
+fn source_message(err: &InputError) -> String {
+    let reason = match err {
+        InputError::YouTubeDlRun(output) => String::from_utf8_lossy(&output.stderr).to_lowercase(),
+        // A search with no results returns no json at all
+        InputError::Json { parsed_text, .. } if parsed_text.trim().is_empty() => return String::from("❌ No results found"),
+        InputError::YouTubeDlUrl(_) | InputError::Metadata => return String::from("❌ No results found"),
+        _ => return String::from("❌ Unable to load that track")
+    };
+
+    if reason.contains("confirm your age") || reason.contains("age-restricted") || reason.contains("inappropriate for some users") {
+        String::from("❌ That video is age-restricted")
+    } else if reason.contains("unavailable") || reason.contains("private video") || reason.contains("removed") {
+        String::from("❌ That video is unavailable")
+    } else if reason.contains("unsupported url") {
+        String::from("❌ That link isn't supported")
+    } else {
+        String::from("❌ Unable to load that track")
+    }
+}
+
+impl Display for AyakaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AyakaError::NotInGuild => f.write_str("Not in a guild"),
+            AyakaError::NotInVoice => f.write_str("Author is not in a voice channel"),
+            AyakaError::VoiceJoin(err) => write!(f, "Unable to join voice channel: {}", err),
+            AyakaError::Source(err) => write!(f, "Error creating music source: {}", err),
+            AyakaError::ChannelNotEmpty => f.write_str("Music channel is not empty"),
+            AyakaError::ChannelInUse => f.write_str("Channel belongs to another player"),
+            AyakaError::VoiceBusy(channel_id) => write!(f, "Voice connection is busy in {:?}", channel_id),
+            AyakaError::GuildUnavailable => f.write_str("Guild actor is not running"),
+            AyakaError::Io(err) => write!(f, "IO error: {}", err),
+            AyakaError::Json(err) => write!(f, "Json error: {}", err),
+            AyakaError::Sqlite(err) => write!(f, "Sqlite error: {}", err),
+            AyakaError::Corrupt(reason) => write!(f, "Corrupt persisted state: {}", reason),
+            AyakaError::Discord(err) => write!(f, "Discord error: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for AyakaError {}
+
+impl From<JoinError> for AyakaError {
+    fn from(err: JoinError) -> Self {
+        AyakaError::VoiceJoin(err)
+    }
+}
+
+impl From<InputError> for AyakaError {
+    fn from(err: InputError) -> Self {
+        AyakaError::Source(err)
+    }
+}
+
+impl From<io::Error> for AyakaError {
+    fn from(err: io::Error) -> Self {
+        AyakaError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for AyakaError {
+    fn from(err: serde_json::Error) -> Self {
+        AyakaError::Json(err)
+    }
+}
+
+impl From<rusqlite::Error> for AyakaError {
+    fn from(err: rusqlite::Error) -> Self {
+        AyakaError::Sqlite(err)
+    }
+}
+
+impl From<SerenityError> for AyakaError {
+    fn from(err: SerenityError) -> Self {
+        AyakaError::Discord(err)
+    }
+}
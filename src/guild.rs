@@ -1,87 +1,391 @@
-use tokio::sync::{Mutex, MutexGuard};
+pub mod actor;
+pub mod lifecycle;
+pub mod player;
+
 use std::sync::Arc;
-use std::collections::HashMap;
 
+use parking_lot::RwLock;
 use serenity::client::{Context};
 
 
-use serenity::model::id::{ChannelId, GuildId};
-use crate::json::GuildJson;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use tracing::error;
+use tracing::log::{Level, log};
+use crate::error::{AyakaError, AyakaResult};
+use crate::guild::player::{BoundPlayer, PlayerId};
+use crate::json::{GuildJson, PlayerJson};
+use crate::storage::DirtyGuilds;
 use crate::member::MemberManager;
 use crate::music::music_manager::MusicManager;
+use crate::music::history::{PlayHistory, SharedHistory};
 use crate::interaction::InteractionManager;
-
-type GuildRegistry<'a> = MutexGuard<'a, HashMap<GuildId, Arc<Mutex<GuildManager>>>>;
-
-lazy_static! {
-    pub static ref GUILD_REGISTRY: Mutex<HashMap<GuildId, Arc<Mutex<GuildManager>>>> = Mutex::new(HashMap::new());
-}
+use crate::interaction::channel::unlock_music_channel;
+use crate::music::state::QueueAction;
+use crate::state::AppState;
 
 #[derive(Debug)]
 pub struct GuildManager {
+    /// The default player, it follows whoever requests a song and is the one 24/7 mode uses
     pub music: MusicManager,
     pub interaction: Option<InteractionManager>,
+    /// Extra music channels tied to one voice channel each
+    pub bound: Vec<BoundPlayer>,
+    /// Player holding the guild's voice connection
+    pub active: PlayerId,
     pub member: MemberManager,
-    pub id: GuildId
+    /// Plays of every player, kept across restarts
+    pub history: SharedHistory,
+    pub id: GuildId,
+    /// Text command prefix, see [AppState::prefix] for the one actually in effect
+    pub prefix: Option<String>,
+    dirty: Arc<DirtyGuilds>
 }
 
 impl GuildManager {
-    /// Constraint: Should only be called on guild join
+    /// Constraint: Should only be called on guild join, see [lifecycle::guild_joined]
     /// Or with specific commands
-    pub fn new(id: GuildId) -> GuildManager {
+    pub fn new(id: GuildId, dirty: Arc<DirtyGuilds>) -> GuildManager {
+        let member = MemberManager::default();
+        let history = SharedHistory::default();
         GuildManager {
-            music: MusicManager::new_no_async(id),
+            music: MusicManager::new_no_async(id, member.shared_preferences(), history.clone()),
             interaction: None,
-            member: MemberManager::default(),
-            id
+            bound: vec![],
+            active: PlayerId::Default,
+            member,
+            history,
+            id,
+            prefix: None,
+            dirty
+        }
+    }
+
+    pub async fn new_channel(&mut self, ctx: &Context, state: &Arc<AppState>, id: ChannelId) -> AyakaResult<()> {
+        if matches!(self.player_in(id), Some(PlayerId::Bound(_))) {
+            return Err(AyakaError::ChannelInUse);
+        }
+        let interaction = InteractionManager::setup(ctx, state, id).await?;
+        // Moving the main channel leaves the old one the way /setup remove would
+        if let Some((old_channel, old_message)) = self.remove_player(state, PlayerId::Default).await && old_channel != id {
+            unlock_music_channel(state, self.id, old_channel).await;
+            if let Some(message_id) = old_message {
+                state.messenger.delete(old_channel, message_id).await.ok();
+            }
+        }
+        self.interaction = Some(interaction);
+        self.cache_channels(state);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Adds a music channel with its own queue that always plays in `voice_channel`
+    pub async fn new_bound_channel(&mut self, ctx: &Context, state: &Arc<AppState>, id: ChannelId, voice_channel: ChannelId) -> AyakaResult<()> {
+        match self.player_in(id) {
+            Some(PlayerId::Default) => return Err(AyakaError::ChannelInUse),
+            Some(player) => { self.remove_player(state, player).await; }
+            None => {}
+        }
+        let interaction = InteractionManager::setup(ctx, state, id).await?;
+        let music = MusicManager::new_no_async(self.id, self.member.shared_preferences(), self.history.clone());
+        self.bound.push(BoundPlayer { voice_channel, music, interaction });
+        self.cache_channels(state);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Stops using a music channel, returns the channel and message that were in use.
+    /// The removed [InteractionManager] is dropped here, which stops its collector.
+    pub async fn remove_player(&mut self, state: &AppState, player: PlayerId) -> Option<(ChannelId, Option<MessageId>)> {
+        let interaction = match player {
+            PlayerId::Default => self.interaction.take()?,
+            PlayerId::Bound(channel_id) => {
+                let index = self.bound.iter().position(|bound| bound.interaction.channel_id == channel_id)?;
+                let BoundPlayer { mut music, interaction, .. } = self.bound.remove(index);
+                // The connection goes back to the default player instead of leaving with the channel,
+                // the removed queue's track is stopped so the default player doesn't carry on playing it
+                if self.active == player {
+                    let was_playing = music.is_playing;
+                    let handler = music.release();
+                    if was_playing && let Some(handler) = &handler {
+                        handler.stop().await;
+                    }
+                    self.music.adopt(handler);
+                    self.active = PlayerId::Default;
+                }
+                interaction
+            }
+        };
+        self.cache_channels(state);
+        self.mark_dirty();
+        Some((interaction.channel_id, interaction.message))
+    }
+
+    /// The player whose control channel is `channel_id`
+    pub fn player_in(&self, channel_id: ChannelId) -> Option<PlayerId> {
+        if self.interaction.as_ref().is_some_and(|interaction| interaction.channel_id == channel_id) {
+            return Some(PlayerId::Default);
+        }
+        self.bound.iter()
+            .find(|bound| bound.interaction.channel_id == channel_id)
+            .map(|bound| PlayerId::Bound(bound.interaction.channel_id))
+    }
+
+    /// Queue and embed of a player, None if it was removed
+    pub fn player_mut(&mut self, player: PlayerId) -> Option<(&mut MusicManager, Option<&mut InteractionManager>)> {
+        match player {
+            PlayerId::Default => Some((&mut self.music, self.interaction.as_mut())),
+            PlayerId::Bound(channel_id) => self.bound.iter_mut()
+                .find(|bound| bound.interaction.channel_id == channel_id)
+                .map(|bound| (&mut bound.music, Some(&mut bound.interaction)))
+        }
+    }
+
+    pub fn interactions(&self) -> impl Iterator<Item = &InteractionManager> {
+        self.interaction.iter().chain(self.bound.iter().map(|bound| &bound.interaction))
+    }
+
+    /// Hands the voice connection to `player`, refused while another player is still playing
+    pub async fn take_voice(&mut self, player: PlayerId) -> AyakaResult<()> {
+        if self.active == player { return Ok(()); }
+
+        let handler = match self.player_mut(self.active) {
+            None => None,
+            Some((music, _)) => {
+                if music.is_playing {
+                    return Err(AyakaError::VoiceBusy(music.current_channel().await));
+                }
+                music.release()
+            }
+        };
+        if let Some((music, _)) = self.player_mut(player) {
+            music.adopt(handler);
+        }
+        self.active = player;
+        Ok(())
+    }
+
+    /// Moves the connection to the 24/7 channel and starts the idle source, unless another player is busy
+    pub async fn resume_stay(&mut self, state: &Arc<AppState>) -> AyakaResult<()> {
+        let channel_id = match self.music.stay_connected {
+            None => return Ok(()),
+            Some(channel_id) => channel_id
+        };
+        self.take_voice(PlayerId::Default).await?;
+        self.music.join_channel(state, channel_id).await?;
+        if !self.music.is_playing {
+            let music_state = self.music.change_track(state, QueueAction::SoftNext).await;
+            if let Some(interaction) = &mut self.interaction {
+                interaction.update_message(state, music_state, QueueAction::HardNext).await;
+            }
         }
+        Ok(())
     }
 
-    pub async fn new_channel(&mut self, ctx: &Context, id: ChannelId) {
-        self.interaction = Some(InteractionManager::new(Some(ctx), id).await);
+    /// None goes back to the configured prefix
+    pub fn set_prefix(&mut self, state: &AppState, prefix: Option<String>) {
+        state.cache_prefix(self.id, prefix.clone());
+        self.prefix = prefix;
+        self.mark_dirty();
     }
 
-    pub async fn register(self) -> Arc<Mutex<GuildManager>> {
-        let id = self.id;
-        let arc = Arc::new(Mutex::new(self));
-        GUILD_REGISTRY.lock().await.insert(id, arc.clone());
-        arc
+    /// Should be called after any player is added or removed, see [AppState::is_music_channel]
+    fn cache_channels(&self, state: &AppState) {
+        state.cache_music_channels(self.id, self.interactions().map(|interaction| interaction.channel_id));
     }
 
-    pub async fn register_already_locked(self, mut registry: GuildRegistry<'_>) -> Arc<Mutex<GuildManager>> {
-        let id = self.id;
-        let arc = Arc::new(Mutex::new(self));
-        registry.insert(id, arc.clone());
-        arc
+    /// Should be called after any change to state that is persisted in [GuildJson]
+    pub fn mark_dirty(&self) {
+        self.dirty.mark(self.id);
     }
 
-    pub async fn init_async(&mut self) {
+    pub async fn init_async(&mut self, ctx: &Context, state: &Arc<AppState>) {
         self.interaction = match &self.interaction {
             None => None,
-            Some(manager) => Some(InteractionManager::new(None, manager.channel_id).await)
+            Some(manager) => Some(InteractionManager::new(ctx, state, manager.channel_id).await)
         };
     }
 
-    pub async fn from_json(json: GuildJson) -> Self {
-        let interaction = if !json.channel_setup || json.music_channel.is_none() { None }
-        else {
-            Some(InteractionManager::new(None, ChannelId(json.music_channel.unwrap())).await)
+    pub async fn from_json(ctx: &Context, state: &Arc<AppState>, json: GuildJson) -> Self {
+        let interaction = match (json.channel_setup, json.music_channel, json.music_message) {
+            (true, Some(channel_id), Some(message_id)) =>
+                Some(InteractionManager::restore(ctx, state, ChannelId(channel_id), MessageId(message_id)).await),
+            (true, Some(channel_id), None) =>
+                Some(InteractionManager::new(ctx, state, ChannelId(channel_id)).await),
+            _ => None
         };
         let guild_id = GuildId(json.guild_id);
-        GuildManager {
-            music: MusicManager::new_no_async(guild_id),
+        let member = MemberManager::from_json(&json.members);
+        let history = Arc::new(RwLock::new(PlayHistory::from_json(&json.history)));
+        let mut bound = Vec::with_capacity(json.players.len());
+        for player in &json.players {
+            let channel_id = ChannelId(player.channel);
+            let interaction = match player.message {
+                Some(message_id) => InteractionManager::restore(ctx, state, channel_id, MessageId(message_id)).await,
+                None => InteractionManager::new(ctx, state, channel_id).await
+            };
+            bound.push(BoundPlayer {
+                voice_channel: ChannelId(player.voice_channel),
+                music: MusicManager::new_no_async(guild_id, member.shared_preferences(), history.clone()),
+                interaction
+            });
+        }
+
+        let mut music = MusicManager::new_no_async(guild_id, member.shared_preferences(), history.clone());
+        music.stay_connected = json.stay_connected.map(ChannelId);
+        music.idle_source = json.idle_source.clone();
+        state.cache_prefix(guild_id, json.prefix.clone());
+        let manager = GuildManager {
+            music,
             interaction,
-            member: MemberManager::default(),
-            id: guild_id
+            bound,
+            active: PlayerId::Default,
+            member,
+            history,
+            id: guild_id,
+            prefix: json.prefix.clone(),
+            dirty: state.dirty.clone()
+        };
+        manager.cache_channels(state);
+        // A replacement message was posted, or the file predates stored message ids
+        let restored = manager.to_json_struct();
+        if restored.music_message != json.music_message || restored.players != json.players {
+            manager.mark_dirty();
         }
+        manager
     }
 
     pub fn to_json_struct(&self) -> GuildJson {
         GuildJson {
             music_channel: self.interaction.as_ref().map(|r| r.channel_id.0),
             channel_setup: self.interaction.as_ref().is_some_and(|i| i.message.is_some()),
-            guild_id: self.id.0
+            guild_id: self.id.0,
+            stay_connected: self.music.stay_connected.map(|c| c.0),
+            idle_source: self.music.idle_source.clone(),
+            music_message: self.interaction.as_ref().and_then(|i| i.message).map(|m| m.0),
+            players: self.bound.iter().map(|bound| PlayerJson {
+                channel: bound.interaction.channel_id.0,
+                voice_channel: bound.voice_channel.0,
+                message: bound.interaction.message.map(|m| m.0)
+            }).collect(),
+            prefix: self.prefix.clone(),
+            members: self.member.to_json(),
+            history: self.history.read().to_json()
         }
     }
 }
 
+/// Rejoins the voice channel of every guild running in 24/7 mode, should be called once on ready
+pub fn connect_stay_channels(state: &Arc<AppState>) {
+    for handle in state.all_guilds() {
+        let state = state.clone();
+        handle.cast(move |guild| Box::pin(async move {
+            let channel_id = match guild.music.stay_connected {
+                None => return,
+                Some(channel_id) => channel_id
+            };
+            match guild.resume_stay(&state).await {
+                Ok(_) => log!(Level::Info, "Rejoined 24/7 channel {} in {}", channel_id, guild.id),
+                Err(err) => error!("Unable to rejoin 24/7 channel {} in {}: {}", channel_id, guild.id, err)
+            }
+        }));
+    }
+}
+
+/// Leaves every voice channel and marks each music message offline, used on shutdown
+pub async fn disconnect_all(state: &Arc<AppState>) {
+    for handle in state.all_guilds() {
+        let state = state.clone();
+        handle.call(move |guild| Box::pin(async move {
+            if let Some((music, _)) = guild.player_mut(guild.active) {
+                if let Err(err) = music.leave(&state).await {
+                    error!("Unable to leave voice in {}: {}", guild.id, err);
+                }
+            }
+            for interaction in guild.interactions() {
+                interaction.set_offline(&state).await;
+            }
+        })).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+
+    use serenity::model::id::{ChannelId, GuildId};
+
+    use crate::config::Config;
+    use crate::error::AyakaError;
+    use crate::guild::GuildManager;
+    use crate::guild::player::{BoundPlayer, PlayerId};
+    use crate::interaction::InteractionManager;
+    use crate::json::JsonGuildStore;
+    use crate::music::music_manager::MusicManager;
+    use crate::platform::fake::{FakeMessenger, FakeSources, FakeVoice};
+    use crate::state::AppState;
+
+    const GUILD: GuildId = GuildId(1);
+    const STAY_CHANNEL: ChannelId = ChannelId(2);
+    const BOUND_VOICE: ChannelId = ChannelId(3);
+    const MUSIC_CHANNEL: ChannelId = ChannelId(4);
+    const BOUND: PlayerId = PlayerId::Bound(MUSIC_CHANNEL);
+
+    /// The default player is connected to the 24/7 channel, `MUSIC_CHANNEL` has its own player with an embed
+    async fn guild_with_bound_player() -> (Arc<AppState>, Arc<FakeVoice>, GuildManager) {
+        let dir = std::env::temp_dir().join("ayaka-guild-test");
+        let store = Arc::new(JsonGuildStore::new(dir.join("guild_cache.json"), dir.join("backups")));
+        let (messenger, voice) = (Arc::new(FakeMessenger::default()), Arc::new(FakeVoice::default()));
+        let state = Arc::new(AppState::offline(Config::default(), store, messenger.clone(), voice.clone(), Arc::new(FakeSources)));
+
+        let mut guild = GuildManager::new(GUILD, state.dirty.clone());
+        guild.music.join_channel(&state, STAY_CHANNEL).await.unwrap();
+        let mut interaction = InteractionManager::new_no_async(MUSIC_CHANNEL);
+        interaction.message = Some(messenger.insert(MUSIC_CHANNEL));
+        let music = MusicManager::new_no_async(GUILD, guild.member.shared_preferences(), guild.history.clone());
+        guild.bound.push(BoundPlayer { voice_channel: BOUND_VOICE, music, interaction });
+        (state, voice, guild)
+    }
+
+    fn stops(voice: &FakeVoice) -> u64 {
+        voice.calls.lock().get(&GUILD).map(|call| call.stops.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn take_voice_hands_over_the_connection() {
+        let (_state, _voice, mut guild) = guild_with_bound_player().await;
+
+        guild.take_voice(BOUND).await.unwrap();
+        assert_eq!(guild.active, BOUND);
+        assert_eq!(guild.music.current_channel().await, None, "the default player kept the connection");
+        assert_eq!(guild.bound[0].music.current_channel().await, Some(STAY_CHANNEL));
+    }
+
+    #[tokio::test]
+    async fn take_voice_refuses_while_another_player_plays() {
+        let (_state, _voice, mut guild) = guild_with_bound_player().await;
+        guild.music.is_playing = true;
+
+        let result = guild.take_voice(BOUND).await;
+        assert!(matches!(result, Err(AyakaError::VoiceBusy(Some(STAY_CHANNEL)))), "{:?}", result);
+        assert_eq!(guild.active, PlayerId::Default);
+        assert_eq!(guild.music.current_channel().await, Some(STAY_CHANNEL));
+    }
+
+    #[tokio::test]
+    async fn removing_the_active_player_hands_voice_back() {
+        let (state, voice, mut guild) = guild_with_bound_player().await;
+        guild.take_voice(BOUND).await.unwrap();
+        guild.bound[0].music.join_channel(&state, BOUND_VOICE).await.unwrap();
+        guild.bound[0].music.is_playing = true;
+        let message = guild.bound[0].interaction.message;
+
+        assert_eq!(guild.remove_player(&state, BOUND).await, Some((MUSIC_CHANNEL, message)));
+        assert!(guild.bound.is_empty());
+        assert_eq!(guild.active, PlayerId::Default);
+        assert_eq!(guild.music.current_channel().await, Some(BOUND_VOICE), "the connection left with the removed player");
+        assert_eq!(stops(&voice), 1, "the removed player's track kept playing");
+        assert!(!guild.music.is_playing);
+        assert_eq!(guild.remove_player(&state, BOUND).await, None);
+    }
+}
@@ -0,0 +1,56 @@
+use serenity::futures::future::BoxFuture;
+use serenity::model::id::GuildId;
+use tokio::sync::{mpsc, oneshot};
+use tracing::log::{Level, log};
+
+use crate::guild::GuildManager;
+use crate::music::history::SharedHistory;
+
+/// A unit of work run by a guild's actor with exclusive access to its [GuildManager]
+pub type GuildJob = Box<dyn for<'a> FnOnce(&'a mut GuildManager) -> BoxFuture<'a, ()> + Send>;
+
+/// Cheap to clone address of a guild's actor task.
+///
+/// Jobs for one guild run one at a time in the order they were sent, jobs for different guilds run concurrently.
+/// A job must never `call` its own guild, it would wait on itself forever.
+#[derive(Clone, Debug)]
+pub struct GuildHandle {
+    pub id: GuildId,
+    /// Same history the manager records to, read directly by lookups that shouldn't wait for the actor
+    pub history: SharedHistory,
+    sender: mpsc::UnboundedSender<GuildJob>
+}
+
+impl GuildHandle {
+    /// Moves the manager into a new actor task that lives until every handle is dropped
+    pub fn spawn(mut manager: GuildManager) -> GuildHandle {
+        let (id, history) = (manager.id, manager.history.clone());
+        let (sender, mut receiver) = mpsc::unbounded_channel::<GuildJob>();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                job(&mut manager).await;
+            }
+            log!(Level::Info, "Guild actor for {} stopped", manager.id);
+        });
+        GuildHandle { id, history, sender }
+    }
+
+    /// Queues a job without waiting for it to run
+    pub fn cast<F>(&self, job: F)
+        where F: for<'a> FnOnce(&'a mut GuildManager) -> BoxFuture<'a, ()> + Send + 'static {
+        if self.sender.send(Box::new(job)).is_err() {
+            log!(Level::Warn, "Dropped job for stopped guild actor {}", self.id);
+        }
+    }
+
+    /// Queues a job and waits for its result, `None` if the actor stopped before running it
+    pub async fn call<R, F>(&self, job: F) -> Option<R>
+        where R: Send + 'static,
+              F: for<'a> FnOnce(&'a mut GuildManager) -> BoxFuture<'a, R> + Send + 'static {
+        let (respond, response) = oneshot::channel();
+        self.cast(move |manager| Box::pin(async move {
+            respond.send(job(manager).await).ok();
+        }));
+        response.await.ok()
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serenity::client::Context;
+use serenity::model::channel::ChannelType;
+use serenity::model::guild::{Guild, UnavailableGuild};
+use serenity::model::id::{ChannelId, GuildId};
+use tracing::error;
+use tracing::log::{Level, log};
+
+use crate::interaction::channel::lock_music_channel;
+use crate::state::AppState;
+
+pub const AUTO_MUSIC_CHANNEL_NAME: &str = "ayaka-music";
+
+/// Sets up a guild the bot was just added to, guilds loaded from storage are left alone
+pub async fn guild_joined(ctx: Context, guild: Guild) {
+    let state = AppState::from_context(&ctx).await;
+    if state.guild(guild.id).is_some() { return; }
+
+    log!(Level::Info, "Joined guild {} ({})", guild.name, guild.id);
+    let handle = state.guild_or_default(guild.id);
+
+    let config = state.config.clone();
+    let music_channel = if config.features.music_channel && config.features.auto_music_channel {
+        create_music_channel(&ctx, &state, &guild).await
+    } else {
+        None
+    };
+
+    if let Some(channel_id) = music_channel {
+        if let Err(err) = lock_music_channel(&state, guild.id, channel_id).await {
+            error!("Unable to lock music channel {}: {}", channel_id, err);
+        }
+        let (job_ctx, job_state) = (ctx.clone(), state.clone());
+        let result = handle.call(move |guild| Box::pin(async move {
+            guild.new_channel(&job_ctx, &job_state, channel_id).await
+        })).await;
+        if let Some(Err(err)) = result {
+            error!("Unable to set up music channel in {}: {}", guild.id, err);
+        }
+    }
+
+    let welcome = match music_channel {
+        Some(channel_id) => format!("Thanks for having me! Type a song name or link in <#{}> to start playing", channel_id),
+        None if config.features.music_channel => String::from("Thanks for having me! Use `/setup` to choose or create a music channel"),
+        None => String::from("Thanks for having me!")
+    };
+    // The music channel only holds the embed, so the welcome goes to the system channel
+    if let Some(channel_id) = guild.system_channel_id {
+        state.messenger.say(channel_id, welcome).await.ok();
+    }
+}
+
+async fn create_music_channel(ctx: &Context, state: &AppState, guild: &Guild) -> Option<ChannelId> {
+    match guild.create_channel(&state.http, |channel| channel
+        .name(AUTO_MUSIC_CHANNEL_NAME)
+        .kind(ChannelType::Text)
+        .topic("Type a song name or link to queue it")).await {
+        Ok(channel) => Some(channel.id),
+        Err(err) => {
+            error!("Unable to create music channel in {}: {}", guild.id, err);
+            if let Some(channel_id) = guild.system_channel_id {
+                channel_id.say(ctx, "❌ Unable to create a music channel, check my permissions or use `/setup`").await.ok();
+            }
+            None
+        }
+    }
+}
+
+/// Forgets a guild some time after being removed from it, an outage is not a removal
+pub async fn guild_left(ctx: Context, incomplete: UnavailableGuild) {
+    if incomplete.unavailable { return; }
+
+    let state = AppState::from_context(&ctx).await;
+    let guild_id = incomplete.id;
+    let grace = Duration::from_secs(state.config.leave_grace);
+    log!(Level::Info, "Removed from guild {}, forgetting it in {:?}", guild_id, grace);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        forget_guild(&state, guild_id).await;
+    });
+}
+
+async fn forget_guild(state: &Arc<AppState>, guild_id: GuildId) {
+    if state.is_shutting_down() { return; }
+    if state.cache.guild(guild_id).is_some() {
+        log!(Level::Info, "Guild {} was rejoined, keeping its settings", guild_id);
+        return;
+    }
+
+    state.cache_prefix(guild_id, None);
+    state.cache_music_channels(guild_id, std::iter::empty());
+    if let Some(handle) = state.remove_guild(guild_id) {
+        let job_state = state.clone();
+        handle.call(move |guild| Box::pin(async move {
+            if let Some((music, _)) = guild.player_mut(guild.active) {
+                if let Err(err) = music.leave(&job_state).await {
+                    error!("Unable to leave voice in {}: {}", guild.id, err);
+                }
+            }
+            guild.interaction = None;
+            guild.bound.clear();
+        })).await;
+    }
+
+    if let Err(err) = state.store.delete_guild(guild_id).and_then(|_| state.store.flush()) {
+        error!("Unable to delete settings of {}: {}", guild_id, err);
+        return;
+    }
+    log!(Level::Info, "Forgot guild {}", guild_id);
+}
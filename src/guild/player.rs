@@ -0,0 +1,21 @@
+use serenity::model::id::ChannelId;
+
+use crate::interaction::InteractionManager;
+use crate::music::music_manager::MusicManager;
+
+/// A music channel with its own embed and queue that always plays in `voice_channel`
+#[derive(Debug)]
+pub struct BoundPlayer {
+    pub voice_channel: ChannelId,
+    pub music: MusicManager,
+    pub interaction: InteractionManager
+}
+
+/// Discord only lets a bot be in one voice channel per guild, so exactly one player holds the connection at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlayerId {
+    /// [crate::guild::GuildManager::music], also used by 24/7 mode
+    Default,
+    /// A [BoundPlayer] by its music channel
+    Bound(ChannelId)
+}
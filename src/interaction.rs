@@ -1,177 +1,572 @@
+pub mod channel;
 pub mod menu;
 pub mod menu_defaults;
 
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use serenity::client::{Context};
+use serenity::client::bridge::gateway::ShardMessenger;
+use serenity::collector::CollectComponentInteraction;
 
 use serenity::model::channel::{Message};
-use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 
+use tokio::task::JoinHandle;
 use tracing::error;
 use tracing::log::{Level, log};
-use crate::arcs::{get_cache_and_http};
-use crate::guild::GUILD_REGISTRY;
+use crate::error::{AyakaError, AyakaResult};
+use crate::guild::GuildManager;
+use crate::guild::player::PlayerId;
+use crate::interaction::channel::unlock_music_channel;
 use crate::interaction::menu::create_interaction;
+use crate::music::music_manager::MusicManager;
 use crate::music::state::{MusicState, QueueAction};
+use crate::platform::{PostedMessage, Source};
+use crate::state::AppState;
 
+const TEMPORARY_REPLY_LIFETIME: Duration = Duration::from_secs(5);
 
 pub struct InteractionHandler;
 
 impl InteractionHandler {
-    pub async fn handle(guild_id: Option<GuildId>, message: Message) {
+    pub async fn handle(state: Arc<AppState>, shard: ShardMessenger, guild_id: Option<GuildId>, message_id: MessageId) {
         log!(Level::Info, "Setup Interaction Handle in {}", guild_id.unwrap_or(GuildId(0)));
-        let chs = get_cache_and_http().await.clone();
-        let manager = GUILD_REGISTRY.lock().await.get(&guild_id.expect("Not a guild message")).expect("Guild not registered").clone();
+        let guild_id = guild_id.expect("Not a guild message");
 
         loop {
-            let interaction = match message.await_component_interaction(&chs.shard).await {
-                None => continue,
+            // None means the collector was closed, asking again would return None forever
+            let interaction = match CollectComponentInteraction::new(&shard).message_id(message_id.0).await {
+                None => {
+                    log!(Level::Info, "Interaction collector for {} closed", message_id);
+                    break;
+                }
                 Some(interaction) => interaction
             };
             println!("Interaction data: {:?}", interaction.data);
-            let mut manager_lock = manager.lock().await;
-            let id = interaction.data.custom_id.as_str();
-            let music = &mut manager_lock.music;
-            let (state, action) = match id {
-                "next" | "prev" => (music.change_track(QueueAction::from(id)).await, QueueAction::from(id)),
-                "stop" => (music.stop_music().await, QueueAction::HardNext),
-                "shuffle" => (music.toggle_shuffle(), QueueAction::StateChange),
-                "loop" => (music.toggle_loop(), QueueAction::StateChange),
-                "queue_select" => {
-                    let index = usize::from_str(interaction.data.values.last().unwrap()).unwrap();
-                    music.cut_line(index);
-                    (music.change_track(QueueAction::SelectedNext).await, QueueAction::SelectedNext)
-                }
-                _ => { (music.get_state(None), QueueAction::StateChange) }
+            // Looked up per interaction since handlers start while guilds are still being loaded
+            let handle = match state.guild(guild_id) {
+                None => continue,
+                Some(handle) => handle
             };
-            if let Some(interaction) = &mut manager_lock.interaction {
-                interaction.update_message(state, action).await;
-            }
+            let state = state.clone();
+            handle.call(move |guild| Box::pin(async move {
+                let id = interaction.data.custom_id.as_str();
+                let (music, embed) = match guild.player_in(interaction.channel_id).and_then(|player| guild.player_mut(player)) {
+                    None => return,
+                    Some(player) => player
+                };
+                let selected = interaction.data.values.last().and_then(|value| usize::from_str(value).ok());
+                let (music_state, action) = apply_control(music, &state, id, selected).await;
+                if let Some(embed) = embed {
+                    embed.update_message(&state, music_state, action).await;
+                }
+
+                interaction.defer(&state.http).await.ok();
+            })).await;
+        }
+    }
+}
 
-            interaction.defer(&chs.http).await.ok();
+/// Runs one of the embed's buttons, `selected` is the queue index picked for `queue_select`.
+/// Shared with the text commands so both behave the same.
+pub async fn apply_control(music: &mut MusicManager, state: &AppState, control: &str, selected: Option<usize>) -> (MusicState, QueueAction) {
+    match (control, selected) {
+        ("next" | "prev", _) => (music.change_track(state, QueueAction::from(control)).await, QueueAction::from(control)),
+        ("stop", _) => (music.stop_music(state).await, QueueAction::HardNext),
+        ("shuffle", _) => (music.toggle_shuffle(), QueueAction::StateChange),
+        ("loop", _) => (music.toggle_loop(), QueueAction::StateChange),
+        ("queue_select", Some(index)) => {
+            music.cut_line(index);
+            (music.change_track(state, QueueAction::SelectedNext).await, QueueAction::SelectedNext)
         }
+        _ => (music.get_state(None), QueueAction::StateChange)
     }
 }
 
 #[derive(Debug)]
 pub struct InteractionManager {
     pub channel_id: ChannelId,
-    pub message: Option<Message>
+    pub message: Option<MessageId>,
+    /// [InteractionHandler] task listening on `message`, stopped when this manager is dropped or replaced
+    collector: Option<JoinHandle<()>>
+}
+
+impl Drop for InteractionManager {
+    fn drop(&mut self) {
+        if let Some(collector) = self.collector.take() {
+            collector.abort();
+        }
+    }
 }
 
 impl InteractionManager {
     pub fn new_no_async(channel_id: ChannelId) -> InteractionManager {
         InteractionManager {
             channel_id,
-            message: None
+            message: None,
+            collector: None
         }
     }
 
-    // Only called when creating a new guild either through json or register
-    pub async fn new(context: Option<&Context>, channel_id: ChannelId) -> InteractionManager {
-        InteractionManager::new_no_async(channel_id).attach_message(context).await
+    /// Posts a fresh music embed, problems are only logged, see [InteractionManager::setup] for the checked version
+    pub async fn new(ctx: &Context, state: &Arc<AppState>, channel_id: ChannelId) -> InteractionManager {
+        InteractionManager::new_no_async(channel_id).attach_message(ctx, state).await
     }
 
-    pub async fn attach_message(mut self, context: Option<&Context>) -> Self {
-        let cache_and_http = get_cache_and_http().await;
-        let messages = match self.channel_id.messages(&cache_and_http.http, |ret| ret.limit(50)).await {
-            Ok(messages) => messages,
-            Err(err) => { error!("{}", err); return self }
-        };
+    /// Used by /setup, refuses channels that already have other people's messages in them
+    pub async fn setup(ctx: &Context, state: &Arc<AppState>, channel_id: ChannelId) -> AyakaResult<InteractionManager> {
+        let mut manager = InteractionManager::new_no_async(channel_id);
+        let messages = own_messages_only(state, channel_id).await?;
+        manager.post_message(ctx, state, messages).await?;
+        Ok(manager)
+    }
 
-        if messages.iter().filter(|m| !m.is_own(&cache_and_http.cache)).count() > 0 {
-            match context {
-                None => {}
-                Some(ctx) => { self.channel_id.say(&ctx, "❌ **Designated music channel must first be empty** ❌").await.ok(); return self }
+    /// Picks up the music message left by a previous run, a new one is only posted if it can't be fetched
+    pub async fn restore(ctx: &Context, state: &Arc<AppState>, channel_id: ChannelId, message_id: MessageId) -> InteractionManager {
+        let mut manager = InteractionManager::new_no_async(channel_id);
+        match state.messenger.is_own(channel_id, message_id).await {
+            Ok(true) => {}
+            Ok(false) => return manager.attach_message(ctx, state).await,
+            Err(err) => {
+                log!(Level::Info, "Music message {} in {} is gone ({}), posting a new one", message_id, channel_id, err);
+                return manager.attach_message(ctx, state).await;
             }
         }
-        for message in messages {
-            message.delete(&cache_and_http).await.ok();
-        };
 
-        let message = match create_interaction(self.channel_id, cache_and_http.http.clone()).await {
-            Ok(message) => message,
+        manager.message = Some(message_id);
+        // Whatever was playing before the restart is gone, including the offline notice left on shutdown
+        if let Err(err) = state.messenger.edit(channel_id, message_id, menu::new_menu(&state.config, MusicState::default())).await {
+            error!("Unable to refresh music embed: {}", err);
+        }
+        manager.spawn_handler(ctx, state, message_id).await;
+        manager
+    }
+
+    pub async fn attach_message(mut self, ctx: &Context, state: &Arc<AppState>) -> Self {
+        let messages = match state.messenger.recent(self.channel_id, 50).await {
+            Ok(messages) => messages,
             Err(err) => { error!("{}", err); return self }
         };
+        if let Err(err) = self.post_message(ctx, state, messages).await {
+            error!("Unable to post music embed in {}: {}", self.channel_id, err);
+        }
+        self
+    }
 
-        self.message = Some(message.clone());
+    /// Clears `old_messages` out of the channel and posts the embed in their place
+    async fn post_message(&mut self, ctx: &Context, state: &Arc<AppState>, old_messages: Vec<PostedMessage>) -> AyakaResult<()> {
+        for message in old_messages {
+            state.messenger.delete(self.channel_id, message.id).await.ok();
+        };
 
-        let guild_id = match match self.channel_id.to_channel_cached(&cache_and_http.cache) {
-            None => self.channel_id.to_channel(&cache_and_http.http).await.ok(),
+        let message_id = state.messenger.send(self.channel_id, create_interaction(&state.config)).await?;
+        self.message = Some(message_id);
+        self.spawn_handler(ctx, state, message_id).await;
+        Ok(())
+    }
+
+    async fn spawn_handler(&mut self, ctx: &Context, state: &Arc<AppState>, message_id: MessageId) {
+        let guild_id = match match self.channel_id.to_channel_cached(&state.cache) {
+            None => self.channel_id.to_channel(&state.http).await.ok(),
             Some(channel) => Some(channel)
         } {
             None => None,
             Some(channel) => channel.guild().map(|channel| channel.guild_id)
         };
-        tokio::spawn(async move { InteractionHandler::handle(guild_id, message).await });
-        self
+        let (state, shard) = (state.clone(), ctx.shard.clone());
+        let collector = tokio::spawn(async move { InteractionHandler::handle(state, shard, guild_id, message_id).await });
+        if let Some(previous) = self.collector.replace(collector) {
+            previous.abort();
+        }
     }
 
-    pub async fn update_message(&mut self, music_state: MusicState, action: QueueAction) {
-        let cache = get_cache_and_http().await;
-
-        if let Some(message) = &mut self.message {
-            let mut new_message = match self.channel_id.message(&cache.http, message.id).await {
-                Err(_err) => return,
-                Ok(message) => message
-            };
-
+    pub async fn update_message(&mut self, state: &AppState, music_state: MusicState, action: QueueAction) {
+        if let Some(message_id) = self.message {
             let edit_message = match action {
                 QueueAction::HardNext | QueueAction::Previous | QueueAction::SelectedNext => {
-                    menu::new_menu(music_state)
+                    menu::new_menu(&state.config, music_state)
                 }
                 QueueAction::SoftNext | QueueAction::StateChange => {
-                    menu::modify_menu(&new_message, music_state)
+                    let current_embed = match state.messenger.embed(self.channel_id, message_id).await {
+                        Err(_err) => return,
+                        Ok(embed) => embed
+                    };
+                    menu::modify_menu(&state.config, current_embed.as_ref(), music_state)
                 }
             };
 
-            match new_message.edit(cache, |builder| {
-                *builder = edit_message;
-                builder
+            if let Err(err) = state.messenger.edit(self.channel_id, message_id, edit_message).await {
+                error!("Unable to edit music embed: {}", err);
             }
-            ).await {
-                Ok(_) => {}
-                Err(err) => {
-                    error!("Unable to edit music embed: {}", err);
-                }
+        }
+    }
+
+    pub async fn set_offline(&self, state: &AppState) {
+        if let Some(message_id) = self.message {
+            if let Err(err) = state.messenger.edit(self.channel_id, message_id, menu::offline_menu(&state.config)).await {
+                error!("Unable to mark music embed offline: {}", err);
             }
         }
     }
 }
 
+/// Posts a new music embed if a player's current one was among the deleted messages
+pub async fn handle_message_delete(ctx: Context, guild_id: GuildId, channel_id: ChannelId, message_ids: Vec<MessageId>) {
+    let state = AppState::from_context(&ctx).await;
+    let handle = match state.guild(guild_id) {
+        None => return,
+        Some(handle) => handle
+    };
+    handle.cast(move |guild| Box::pin(async move {
+        let embed = match guild.player_in(channel_id).and_then(|player| guild.player_mut(player)) {
+            Some((_, Some(embed))) => embed,
+            _ => return
+        };
+        if !embed.message.is_some_and(|message_id| message_ids.contains(&message_id)) {
+            return;
+        }
+
+        log!(Level::Info, "Music embed in {} was deleted, posting a new one", channel_id);
+        // Replacing the manager drops the old one, which stops its collector
+        *embed = InteractionManager::new(&ctx, &state, channel_id).await;
+        guild.mark_dirty();
+    }));
+}
+
+/// Forgets a music channel if it or the voice channel it is bound to was deleted
+pub async fn handle_channel_delete(ctx: Context, guild_id: GuildId, channel_id: ChannelId) {
+    let state = AppState::from_context(&ctx).await;
+    let handle = match state.guild(guild_id) {
+        None => return,
+        Some(handle) => handle
+    };
+    handle.cast(move |guild| Box::pin(async move {
+        let bound_to_channel = guild.bound.iter()
+            .filter(|bound| bound.voice_channel == channel_id)
+            .map(|bound| PlayerId::Bound(bound.interaction.channel_id))
+            .collect::<Vec<PlayerId>>();
+        for player in guild.player_in(channel_id).into_iter().chain(bound_to_channel) {
+            let (music_channel, message) = match guild.remove_player(&state, player).await {
+                None => continue,
+                Some(removed) => removed
+            };
+            log!(Level::Info, "Channel {} in {} was deleted, removed music channel {}", channel_id, guild.id, music_channel);
+            // Only the voice channel is gone, the music channel goes back to normal like /setup remove leaves it
+            if music_channel != channel_id {
+                if let Some(message_id) = message {
+                    state.messenger.delete(music_channel, message_id).await.ok();
+                }
+                unlock_music_channel(&state, guild_id, music_channel).await;
+            }
+        }
+    }));
+}
+
 pub async fn handle_message(ctx: Context, msg: Message) -> Option<()> {
     let guild_id = msg.guild_id?;
-    let acquire_lock = GUILD_REGISTRY.lock().await;
-    let guild_manager = acquire_lock.get(&guild_id)?;
-    let mut guild_lock = guild_manager.lock().await;
-    if msg.channel_id != guild_lock.interaction.as_ref()?.channel_id { return None; }
+    let state = AppState::from_context(&ctx).await;
+    // Most messages aren't in a music channel, those never wait on the guild's actor
+    if !state.is_music_channel(msg.channel_id) { return None; }
+    let handle = state.guild(guild_id)?;
+    let (channel_id, author) = (msg.channel_id, msg.author.id);
 
     msg.delete(&ctx).await.ok();
+    // Text commands are run by the framework, they only need to be cleaned out of the channel
+    if state.config.features.prefix_commands && msg.content.starts_with(&state.prefix(guild_id)) {
+        return Some(());
+    }
+    if msg.content.ends_with("setup") { return Some(()); }
 
-    let music = &mut guild_lock.music;
+    let source = match resolve_request(&state, msg.content).await {
+        Ok(source) => source,
+        Err(err) => {
+            error!("Unable to find a track in {}: {}", guild_id, err);
+            send_temporary_reply(&state, channel_id, err.user_message()).await;
+            return Some(());
+        }
+    };
+    handle.cast(move |guild| Box::pin(async move {
+        // The channel can stop being a music channel while the search runs
+        let player = match guild.player_in(channel_id) {
+            None => return,
+            Some(player) => player
+        };
+        if let Err(err) = queue_and_play(guild, player, &state, author, source).await {
+            error!("Unable to queue in {}: {}", guild.id, err);
+            send_temporary_reply(&state, channel_id, err.user_message()).await;
+        }
+    }));
+    Some(())
+}
 
-    if music.try_join(&ctx, &msg, msg.guild(&ctx)).await.is_err() {
-        return None;
+/// The recent messages of a channel /setup wants to take over, as long as the bot posted all of them
+async fn own_messages_only(state: &AppState, channel_id: ChannelId) -> AyakaResult<Vec<PostedMessage>> {
+    let messages = state.messenger.recent(channel_id, 50).await?;
+    if messages.iter().any(|message| !message.own) {
+        return Err(AyakaError::ChannelNotEmpty);
     }
+    Ok(messages)
+}
 
-    let search = msg.content;
-    if search.ends_with("setup") { return None; }
+/// Music channels only hold the embed, so replies posted in one are cleaned up shortly after
+pub async fn send_temporary_reply(state: &Arc<AppState>, channel_id: ChannelId, content: String) {
+    match state.messenger.say(channel_id, content).await {
+        Ok(reply_id) => delete_later(state, channel_id, reply_id),
+        Err(err) => error!("Unable to send reply in {}: {}", channel_id, err)
+    }
+}
 
+/// Deletes a reply after [TEMPORARY_REPLY_LIFETIME], for replies that can't be sent through [send_temporary_reply]
+pub fn delete_later(state: &AppState, channel_id: ChannelId, message_id: MessageId) {
+    let messenger = state.messenger.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(TEMPORARY_REPLY_LIFETIME).await;
+        messenger.delete(channel_id, message_id).await.ok();
+    });
+}
+
+/// Looks up a link or search, done before entering the guild's actor so a slow lookup doesn't hold up the guild
+pub async fn resolve_request(state: &AppState, search: String) -> AyakaResult<Source> {
     if search.starts_with("http") {
-        music.queue(search).await;
+        state.sources.resolve(search).await
     } else {
-        music.search_and_queue(search).await;
+        state.sources.search(search).await
+    }
+}
+
+/// Queues `source` on `player`, joining the author's voice channel unless the player is bound to one
+pub async fn queue_and_play(guild: &mut GuildManager, player: PlayerId, state: &Arc<AppState>, author: UserId, source: Source) -> AyakaResult<()> {
+    guild.take_voice(player).await?;
+    let bound_voice = guild.bound.iter()
+        .find(|bound| PlayerId::Bound(bound.interaction.channel_id) == player)
+        .map(|bound| bound.voice_channel);
+    let (music, embed) = guild.player_mut(player).ok_or(AyakaError::GuildUnavailable)?;
+
+    match bound_voice {
+        Some(voice_channel) => music.join_channel(state, voice_channel).await?,
+        None => music.try_join(state, author).await?
     }
 
+    let queued = music.push(source, Some(author));
+
     let (metadata, action) = if !music.is_playing {
-        (music.change_track(QueueAction::SoftNext).await, QueueAction::HardNext)
+        (music.change_track(state, QueueAction::SoftNext).await, QueueAction::HardNext)
     } else {
-        (
-            MusicState { metadata: None, queue_names: music.get_items_in_queue(), looping: music.looping, shuffling: music.shuffling },
-            QueueAction::StateChange
-        )
+        (music.get_state(None), QueueAction::StateChange)
     };
-    guild_lock.interaction.as_mut()?.update_message(metadata, action).await;
-    Some(())
-}
\ No newline at end of file
+    if let Some(embed) = embed {
+        embed.update_message(state, metadata, action).await;
+    }
+
+    if state.config.features.member_tracking {
+        guild.member.record_request(author, &queued);
+        guild.mark_dirty();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+
+    use serenity::model::id::{ChannelId, GuildId, UserId};
+
+    use crate::config::Config;
+    use crate::error::AyakaError;
+    use crate::guild::GuildManager;
+    use crate::guild::player::{BoundPlayer, PlayerId};
+    use crate::json::JsonGuildStore;
+    use crate::music::music_manager::MusicManager;
+    use crate::music::state::QueueAction;
+    use crate::platform::fake::{FakeMessenger, FakeSources, FakeVoice};
+    use crate::state::AppState;
+    use super::{apply_control, own_messages_only, queue_and_play, resolve_request, InteractionManager};
+
+    const GUILD: GuildId = GuildId(1);
+    const VOICE: ChannelId = ChannelId(2);
+    const MUSIC_CHANNEL: ChannelId = ChannelId(3);
+    const MEMBER: UserId = UserId(4);
+    const PLAYER: PlayerId = PlayerId::Bound(MUSIC_CHANNEL);
+
+    struct Session {
+        state: Arc<AppState>,
+        messenger: Arc<FakeMessenger>,
+        voice: Arc<FakeVoice>,
+        guild: GuildManager
+    }
+
+    impl Session {
+        /// A music channel bound to a voice channel, so nothing has to look up where the member is
+        fn new() -> Session {
+            let (messenger, voice) = (Arc::new(FakeMessenger::default()), Arc::new(FakeVoice::default()));
+            let dir = std::env::temp_dir().join("ayaka-session-test");
+            let store = Arc::new(JsonGuildStore::new(dir.join("guild_cache.json"), dir.join("backups")));
+            let state = Arc::new(AppState::offline(Config::default(), store, messenger.clone(), voice.clone(), Arc::new(FakeSources)));
+
+            let mut guild = GuildManager::new(GUILD, state.dirty.clone());
+            let mut interaction = InteractionManager::new_no_async(MUSIC_CHANNEL);
+            interaction.message = Some(messenger.insert(MUSIC_CHANNEL));
+            let music = MusicManager::new_no_async(GUILD, guild.member.shared_preferences(), guild.history.clone());
+            guild.bound.push(BoundPlayer { voice_channel: VOICE, music, interaction });
+            Session { state, messenger, voice, guild }
+        }
+
+        async fn request(&mut self, search: &str) {
+            let source = resolve_request(&self.state, search.to_string()).await.unwrap();
+            queue_and_play(&mut self.guild, PLAYER, &self.state, MEMBER, source).await.unwrap();
+        }
+
+        /// Same as pressing one of the embed's buttons
+        async fn press(&mut self, control: &str) {
+            let (music, embed) = self.guild.player_mut(PLAYER).unwrap();
+            let selected = music.get_items_in_queue().last().map(|item| item.index);
+            let (music_state, action) = apply_control(music, &self.state, control, selected).await;
+            embed.unwrap().update_message(&self.state, music_state, action).await;
+        }
+
+        /// What [crate::music::music_manager::TrackEndEvent] does when songbird finishes a track
+        async fn track_ends(&mut self) {
+            let (music, embed) = self.guild.player_mut(PLAYER).unwrap();
+            let music_state = music.change_track(&self.state, QueueAction::SoftNext).await;
+            embed.unwrap().update_message(&self.state, music_state, QueueAction::HardNext).await;
+        }
+
+        fn is_playing(&mut self) -> bool {
+            self.guild.player_mut(PLAYER).unwrap().0.is_playing
+        }
+
+        fn played(&self) -> Vec<String> {
+            let call = self.voice.calls.lock().get(&GUILD).cloned().unwrap();
+            let played = call.played.lock();
+            played.iter().flatten().cloned().collect()
+        }
+
+        fn volumes(&self) -> Vec<f32> {
+            self.voice.calls.lock().get(&GUILD).map(|call| call.volumes.lock().clone()).unwrap_or_default()
+        }
+
+        fn stops(&self) -> u64 {
+            self.voice.calls.lock().get(&GUILD).map(|call| call.stops.load(Ordering::Relaxed)).unwrap_or_default()
+        }
+
+        /// Field of the embed as last sent to discord
+        fn embed_field(&self, pointer: &str) -> Option<String> {
+            let message_id = self.guild.bound[0].interaction.message?;
+            let messages = self.messenger.messages.lock();
+            let edit = messages.get(&message_id)?.edits.last()?;
+            edit.get("embeds")?.pointer(pointer)?.as_str().map(String::from)
+        }
+    }
+
+    fn titles(titles: &[&str]) -> Vec<String> {
+        titles.iter().map(|title| format!("song {}", title)).collect()
+    }
+
+    #[tokio::test]
+    async fn queue_skip_loop_shuffle_session() {
+        let mut session = Session::new();
+
+        session.request("song a").await;
+        session.request("song b").await;
+        session.request("song c").await;
+        assert_eq!(session.played(), titles(&["a"]), "queued tracks started while one was playing");
+        assert_eq!(session.embed_field("/0/title").as_deref(), Some("**song a**"));
+
+        session.press("next").await;
+        assert_eq!(session.played(), titles(&["a", "b"]));
+        assert_eq!(session.stops(), 1, "skipping didn't stop the current track");
+        assert_eq!(session.embed_field("/0/title").as_deref(), Some("**song b**"));
+
+        session.track_ends().await;
+        session.track_ends().await;
+        assert_eq!(session.played(), titles(&["a", "b", "c"]));
+        assert!(!session.is_playing(), "still playing after the queue ran out");
+
+        // A request after the queue ended starts straight away
+        session.request("song d").await;
+        session.press("prev").await;
+        assert_eq!(session.played(), titles(&["a", "b", "c", "d", "c"]));
+
+        session.press("loop").await;
+        session.track_ends().await;
+        session.track_ends().await;
+        assert_eq!(session.played(), titles(&["a", "b", "c", "d", "c", "d", "c"]), "looping didn't repeat from the current track");
+
+        session.press("loop").await;
+        session.press("shuffle").await;
+        assert_eq!(session.embed_field("/0/footer/text").as_deref(), Some("Looping: False | Shuffling: True"));
+        // Shuffling never repeats the current track, with two left they take turns
+        session.track_ends().await;
+        session.track_ends().await;
+        assert_eq!(session.played()[7..], titles(&["d", "c"]));
+
+        session.press("shuffle").await;
+        session.press("stop").await;
+        assert!(!session.is_playing());
+        assert_eq!(session.played().len(), 9, "stopping played something");
+
+        session.request("song e").await;
+        session.request("song f").await;
+        session.request("song g").await;
+        session.press("queue_select").await;
+        assert_eq!(session.played()[9..], titles(&["e", "g"]), "picking from the queue didn't jump to the pick");
+        assert_eq!(session.embed_field("/0/title").as_deref(), Some("**song g**"));
+    }
+
+    #[tokio::test]
+    async fn requester_preferences_apply_when_their_track_starts() {
+        let mut session = Session::new();
+        session.guild.member.update_preferences(MEMBER, |preferences| {
+            preferences.volume = Some(50);
+            preferences.dm_now_playing = true;
+        });
+
+        session.request("song a").await;
+        assert_eq!(session.volumes(), vec![0.5]);
+        // The DM is sent in the background
+        tokio::task::yield_now().await;
+        let dms = session.messenger.dms.lock().clone();
+        assert_eq!(dms.len(), 1);
+        assert_eq!(dms[0].0, MEMBER);
+        assert!(dms[0].1.contains("song a"), "DM doesn't name the track: {}", dms[0].1);
+
+        session.press("loop").await;
+        session.track_ends().await;
+        tokio::task::yield_now().await;
+        assert_eq!(session.played(), titles(&["a", "a"]));
+        assert_eq!(session.messenger.dms.lock().len(), 1, "a looped track sent another DM");
+    }
+
+    #[tokio::test]
+    async fn tracks_blocked_by_a_listener_are_skipped_on_replay() {
+        let mut session = Session::new();
+        session.voice.listeners.lock().insert(VOICE, vec![MEMBER]);
+        session.guild.member.update_preferences(MEMBER, |preferences| {
+            preferences.blocked.push(String::from("https://fake.invalid/song-a"));
+        });
+
+        // Requesting it still plays it once
+        session.request("song a").await;
+        session.request("song b").await;
+        session.press("loop").await;
+        session.track_ends().await;
+        session.track_ends().await;
+        assert_eq!(session.played(), titles(&["a", "b", "b"]), "the blocked track was replayed");
+    }
+
+    #[tokio::test]
+    async fn setup_refuses_a_channel_with_member_messages() {
+        let session = Session::new();
+        let channel_id = ChannelId(5);
+        session.messenger.insert(channel_id);
+        assert_eq!(own_messages_only(&session.state, channel_id).await.map(|messages| messages.len()).ok(), Some(1));
+
+        session.messenger.insert_foreign(channel_id);
+        let result = own_messages_only(&session.state, channel_id).await;
+        assert!(matches!(result, Err(AyakaError::ChannelNotEmpty)), "{:?}", result);
+        assert_eq!(session.messenger.in_channel(channel_id).len(), 2, "refusing the channel changed what is in it");
+    }
+}
@@ -0,0 +1,71 @@
+use serenity::model::channel::{PermissionOverwrite, PermissionOverwriteType};
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use serenity::model::permissions::Permissions;
+use tracing::error;
+
+use crate::error::{AyakaError, AyakaResult};
+use crate::state::AppState;
+
+/// Seconds between song requests from the same member
+pub const MUSIC_CHANNEL_SLOW_MODE: u64 = 3;
+
+/// Denied to everyone so the channel only ever holds the embed and requests the bot is about to delete
+const LOCKED_FOR_EVERYONE: Permissions = Permissions::ADD_REACTIONS
+    .union(Permissions::ATTACH_FILES)
+    .union(Permissions::CREATE_PUBLIC_THREADS)
+    .union(Permissions::CREATE_PRIVATE_THREADS)
+    .union(Permissions::SEND_MESSAGES_IN_THREADS);
+
+/// What the bot needs to keep the channel clean regardless of the guild's role setup
+const BOT_ALLOWED: Permissions = Permissions::VIEW_CHANNEL
+    .union(Permissions::SEND_MESSAGES)
+    .union(Permissions::EMBED_LINKS)
+    .union(Permissions::READ_MESSAGE_HISTORY)
+    .union(Permissions::MANAGE_MESSAGES);
+
+/// Applies slow-mode and the locked permissions, existing overwrites for @everyone are kept
+pub async fn lock_music_channel(state: &AppState, guild_id: GuildId, channel_id: ChannelId) -> AyakaResult<()> {
+    let channel = channel_id.to_channel(&state.http).await?.guild().ok_or(AyakaError::NotInGuild)?;
+    let everyone = PermissionOverwriteType::Role(RoleId(guild_id.0));
+    let (allow, deny) = channel.permission_overwrites.iter()
+        .find(|overwrite| overwrite.kind == everyone)
+        .map(|overwrite| (overwrite.allow, overwrite.deny))
+        .unwrap_or((Permissions::empty(), Permissions::empty()));
+
+    channel_id.create_permission(&state.http, &PermissionOverwrite {
+        allow: allow - LOCKED_FOR_EVERYONE,
+        deny: deny | LOCKED_FOR_EVERYONE,
+        kind: everyone
+    }).await?;
+    channel_id.create_permission(&state.http, &PermissionOverwrite {
+        allow: BOT_ALLOWED,
+        deny: Permissions::empty(),
+        kind: PermissionOverwriteType::Member(state.cache.current_user_id())
+    }).await?;
+    channel_id.edit(&state.http, |edit| edit.rate_limit_per_user(MUSIC_CHANNEL_SLOW_MODE)).await?;
+    Ok(())
+}
+
+/// Undoes [lock_music_channel] as far as possible, failures are logged since the channel is no longer ours
+pub async fn unlock_music_channel(state: &AppState, guild_id: GuildId, channel_id: ChannelId) {
+    let channel = match channel_id.to_channel(&state.http).await.map(|channel| channel.guild()) {
+        Ok(Some(channel)) => channel,
+        _ => return
+    };
+
+    let everyone = PermissionOverwriteType::Role(RoleId(guild_id.0));
+    if let Some(overwrite) = channel.permission_overwrites.iter().find(|overwrite| overwrite.kind == everyone) {
+        let result = channel_id.create_permission(&state.http, &PermissionOverwrite {
+            allow: overwrite.allow,
+            deny: overwrite.deny - LOCKED_FOR_EVERYONE,
+            kind: everyone
+        }).await;
+        if let Err(err) = result {
+            error!("Unable to unlock {}: {}", channel_id, err);
+        }
+    }
+    channel_id.delete_permission(&state.http, PermissionOverwriteType::Member(state.cache.current_user_id())).await.ok();
+    if let Err(err) = channel_id.edit(&state.http, |edit| edit.rate_limit_per_user(0)).await {
+        error!("Unable to turn off slow-mode in {}: {}", channel_id, err);
+    }
+}
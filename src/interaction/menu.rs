@@ -1,15 +1,13 @@
-use std::sync::Arc;
-use serenity::builder::{CreateComponents, CreateSelectMenuOption, EditMessage};
-use serenity::http::Http;
-use serenity::model::channel::Message;
-use serenity::model::id::ChannelId;
-use crate::interaction::menu_defaults::{default_components, default_embed, MUSIC_EMBED_COLOR, MUSIC_EMBED_IMAGE, MUSIC_EMBED_TITLE};
+use serenity::builder::{CreateComponents, CreateMessage, CreateSelectMenuOption, EditMessage};
+use serenity::model::channel::Embed;
+use crate::config::Config;
+use crate::interaction::menu_defaults::{default_components, default_embed, MUSIC_EMBED_TITLE, OFFLINE_EMBED_DESCRIPTION, OFFLINE_EMBED_TITLE};
 use crate::music::state::{MusicState, QueueItem};
 use crate::troll;
 
-pub fn new_menu(music_state: MusicState) -> EditMessage<'static> {
+pub fn new_menu(config: &Config, music_state: MusicState) -> EditMessage<'static> {
     let metadata = music_state.metadata.unwrap_or_default();
-    let mut default_embed = default_embed();
+    let mut default_embed = default_embed(config);
     metadata.thumbnail.map(|str| default_embed.image(str));
     metadata.title.map(|str| "**".to_owned() + &str + "**").map(|str| default_embed.title(str));
     metadata.source_url.map(|url| default_embed.url(url));
@@ -46,24 +44,36 @@ pub fn new_menu(music_state: MusicState) -> EditMessage<'static> {
 }
 
 /// Updates info such as looping, shuffling, and queue
-pub fn modify_menu(current: &Message, music_state: MusicState) -> EditMessage<'static> {
-    let current_embed = current.embeds.get(0).expect("No embeds attached to this message");
+/// Falls back to the default embed's fields when `current_embed` is missing
+pub fn modify_menu(config: &Config, current_embed: Option<&Embed>, music_state: MusicState) -> EditMessage<'static> {
     let mut edit_message = EditMessage::default();
     edit_message
         .add_embed(|em| {
             let em = em
-                .title(current_embed.title.clone().unwrap_or_else(|| MUSIC_EMBED_TITLE.to_string()))
-                .image(current_embed.image.clone().map(|img| img.url).unwrap_or_else(|| MUSIC_EMBED_IMAGE.to_string()))
-                .color(MUSIC_EMBED_COLOR)
-                .description(current_embed.description.clone().unwrap_or_else(|| troll::random_ayaka_quote().to_string()))
+                .title(current_embed.and_then(|e| e.title.clone()).unwrap_or_else(|| MUSIC_EMBED_TITLE.to_string()))
+                .image(current_embed.and_then(|e| e.image.clone()).map(|img| img.url).unwrap_or_else(|| config.embed.image.clone()))
+                .color(config.embed_color())
+                .description(current_embed.and_then(|e| e.description.clone()).unwrap_or_else(|| troll::random_ayaka_quote().to_string()))
                 .footer(|f| f.text(format!("Looping: {} | Shuffling: {}", upcase_bool(music_state.looping), upcase_bool(music_state.shuffling))));
-            current_embed.clone().url.map(|url| em.url(url));
+            current_embed.and_then(|e| e.url.clone()).map(|url| em.url(url));
             em
         })
         .set_components(create_queue_component(&music_state.queue_names));
     edit_message
 }
 
+/// Left on the music message during shutdown, the buttons are removed since nothing would answer them
+pub fn offline_menu(config: &Config) -> EditMessage<'static> {
+    let mut embed = default_embed(config);
+    embed.title(OFFLINE_EMBED_TITLE).description(OFFLINE_EMBED_DESCRIPTION);
+
+    let mut edit_message = EditMessage::default();
+    edit_message
+        .set_embed(embed)
+        .set_components(CreateComponents::default());
+    edit_message
+}
+
 pub fn create_queue_component(queue_items: &Vec<QueueItem>) -> CreateComponents {
     let mut default = default_components();
     if queue_items.is_empty() { return default; }
@@ -87,12 +97,13 @@ pub fn create_queue_component(queue_items: &Vec<QueueItem>) -> CreateComponents
     default
 }
 
-pub async fn create_interaction(channel_id: ChannelId, http: Arc<Http>) -> serenity::Result<Message> {
-    channel_id.send_message(&http, |builder| builder
+pub fn create_interaction(config: &Config) -> CreateMessage<'static> {
+    let mut message = CreateMessage::default();
+    message
         //.content("**__Queue List__**\nJoin a voice channel and queue songs by name or url by posting in this channel.")
-        .set_embed(default_embed())
-        .set_components(default_components())//.create_action_row(|row| row.create_select_menu(|menu| menu.placeholder("Queue").custom_id("Queue").options(|o| {*o = test_option(); o})))
-    ).await
+        .set_embed(default_embed(config))
+        .set_components(default_components());//.create_action_row(|row| row.create_select_menu(|menu| menu.placeholder("Queue").custom_id("Queue").options(|o| {*o = test_option(); o})))
+    message
 }
 
 fn upcase_bool(b: bool) -> String {
@@ -1,19 +1,24 @@
 use serenity::builder::{CreateComponents, CreateEmbed};
 use serenity::model::application::component::ButtonStyle;
 use serenity::utils::Color;
+use crate::config::Config;
 use crate::troll;
 
 pub const MUSIC_EMBED_TITLE: &str = "No song currently playing";
+/// Default for `embed.color` in the config file
 pub const MUSIC_EMBED_COLOR: Color = Color::from_rgb(120, 107, 199);
+/// Default for `embed.image` in the config file
 pub const MUSIC_EMBED_IMAGE: &str = "https://cdn.discordapp.com/attachments/893017931087245325/1047929174406471711/ayaka.PNG";
 pub const MUSIC_EMBED_FOOTER_TEXT: &str = "Looping: False | Shuffling: False";
+pub const OFFLINE_EMBED_TITLE: &str = "Music is offline";
+pub const OFFLINE_EMBED_DESCRIPTION: &str = "Controls will come back when the bot restarts";
 
-pub fn default_embed() -> CreateEmbed {
+pub fn default_embed(config: &Config) -> CreateEmbed {
     let mut embed = CreateEmbed::default();
     embed.title(MUSIC_EMBED_TITLE)
         .description(troll::random_ayaka_quote())
-        .color(MUSIC_EMBED_COLOR)
-        .image(MUSIC_EMBED_IMAGE)
+        .color(config.embed_color())
+        .image(&config.embed.image)
         .footer(|footer| footer.text(MUSIC_EMBED_FOOTER_TEXT));
     embed
 
@@ -1,88 +1,286 @@
+pub mod migration;
 
-use std::fs::File;
-use std::io::{ErrorKind, Read, Write};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use serde::Deserialize;
 use serde::Serialize;
-use tokio::sync::Mutex;
+use chrono::Utc;
+use parking_lot::Mutex;
+use serenity::model::id::GuildId;
 use tracing::error;
-use crate::guild::{GUILD_REGISTRY, GuildManager};
+use tracing::log::{Level, log};
+use crate::error::{AyakaError, AyakaResult};
+use crate::json::migration::CURRENT_SCHEMA_VERSION;
+use crate::storage::GuildStore;
 
-const GUILD_JSON_FILE: &str = "guild_cache.json";
-const BACKUP_GUILD_JSON_FILE: &str = "guild_cache-backup.json";
-lazy_static! {
-    static ref SAVE_COUNT: AtomicUsize = AtomicUsize::default();
-}
+/// Single backup file used before backups were rotated, still read as a last resort
+const LEGACY_BACKUP_GUILD_JSON_FILE: &str = "guild_cache-backup.json";
+const BACKUP_PREFIX: &str = "guild_cache-";
+const MAX_BACKUPS: usize = 10;
+const BACKUP_EVERY_N_SAVES: usize = 4;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct GuildCfgFile {
+    /// See [migration::CURRENT_SCHEMA_VERSION], missing in files written before versioning
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
     pub guilds: Vec<GuildJson>
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+impl GuildCfgFile {
+    pub fn new(guilds: Vec<GuildJson>) -> GuildCfgFile {
+        GuildCfgFile { version: CURRENT_SCHEMA_VERSION, guilds }
+    }
+}
+
+/// Every field falls back to its default so a missing setting never fails the whole file
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
 pub struct GuildJson {
     pub music_channel: Option<u64>,
     pub channel_setup: bool,
-    pub guild_id: u64
+    pub guild_id: u64,
+    /// Voice channel the bot should never leave (24/7 mode)
+    pub stay_connected: Option<u64>,
+    /// Playlist or radio url played while the queue is empty in 24/7 mode
+    pub idle_source: Option<String>,
+    /// The music embed in `music_channel`, reattached on restart instead of posting a new one
+    pub music_message: Option<u64>,
+    /// Extra music channels, each bound to a voice channel
+    pub players: Vec<PlayerJson>,
+    /// Prefix for text commands, the configured prefix is used when unset
+    pub prefix: Option<String>,
+    /// Listening statistics, see [crate::member::MemberManager]
+    pub members: Vec<MemberJson>,
+    /// Every track played, oldest first, see [crate::music::history::PlayHistory]
+    pub history: Vec<HistoryJson>
 }
 
-pub async fn load_guilds_to_cache() -> Result<(), String> {
-    let mut input = String::new();
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct PlayerJson {
+    pub channel: u64,
+    pub voice_channel: u64,
+    pub message: Option<u64>
+}
 
-    match File::options().read(true).open(GUILD_JSON_FILE) {
-        Ok(mut file) => file.read_to_string(&mut input).ok(),
-        Err(err) => return match err.kind() {
-            ErrorKind::NotFound => {
-                match File::options().truncate(true).write(true).create_new(true).open(GUILD_JSON_FILE) {
-                    Ok(_) => Ok(()),
-                    Err(err) => Err(err.to_string())
-                }
-            }
-            _ => { Err(err.to_string()) }
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MemberJson {
+    pub user_id: u64,
+    pub tracks_requested: u64,
+    pub listening_secs: u64,
+    /// Title and times requested, most requested first
+    pub tracks: Vec<(String, u64)>,
+    pub artists: Vec<(String, u64)>,
+    /// Preferences, see [crate::member::MemberPreferences]
+    pub volume: Option<u32>,
+    pub dm_now_playing: bool,
+    pub blocked: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct HistoryJson {
+    /// What /history and /replay call the play, 0 in files saved before plays were numbered
+    pub number: u64,
+    /// Unix timestamp in seconds
+    pub played_at: i64,
+    pub requester: Option<u64>,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub duration_secs: Option<u64>
+}
+
+/// Keeps every guild in one json file, writes are buffered until [GuildStore::flush]
+pub struct JsonGuildStore {
+    path: PathBuf,
+    backup_dir: PathBuf,
+    /// Last persisted state of every guild, the whole file is rewritten from this on flush
+    snapshots: Mutex<HashMap<GuildId, GuildJson>>,
+    save_count: AtomicUsize
+}
+
+impl JsonGuildStore {
+    pub fn new(path: impl Into<PathBuf>, backup_dir: impl Into<PathBuf>) -> JsonGuildStore {
+        JsonGuildStore {
+            path: path.into(),
+            backup_dir: backup_dir.into(),
+            snapshots: Mutex::new(HashMap::new()),
+            save_count: AtomicUsize::default()
         }
-    };
+    }
+}
 
-    if !input.is_empty() {
-        let cfg: GuildCfgFile = match serde_json::from_str(&input) {
+impl GuildStore for JsonGuildStore {
+    fn load_guilds(&self) -> AyakaResult<Vec<GuildJson>> {
+        let cfg = match read_guild_cfg(&self.path) {
             Ok(cfg) => cfg,
-            Err(err) => return Err(err.to_string())
+            Err(err) => {
+                error!("Unable to read {}: {}, falling back to backups", self.path.display(), err);
+                match read_newest_backup(&self.backup_dir) {
+                    Some(cfg) => cfg,
+                    None if err.kind() == ErrorKind::NotFound => GuildCfgFile::new(vec![]),
+                    None => return Err(AyakaError::Corrupt(format!("{} is unreadable and no valid backup was found ({})", self.path.display(), err)))
+                }
+            }
         };
 
-        let mut registry = GUILD_REGISTRY.lock().await;
-        for guild_json in cfg.guilds {
-            let guild_manager = GuildManager::from_json(guild_json).await;
-            registry.insert(guild_manager.id, Arc::new(Mutex::new(guild_manager)));
+        let mut snapshots = self.snapshots.lock();
+        for guild_json in &cfg.guilds {
+            snapshots.insert(GuildId(guild_json.guild_id), guild_json.clone());
         }
+        Ok(cfg.guilds)
+    }
+
+    fn save_guild(&self, guild: GuildJson) -> AyakaResult<()> {
+        self.snapshots.lock().insert(GuildId(guild.guild_id), guild);
+        Ok(())
+    }
+
+    fn delete_guild(&self, guild_id: GuildId) -> AyakaResult<()> {
+        self.snapshots.lock().remove(&guild_id);
+        Ok(())
+    }
+
+    fn flush(&self) -> AyakaResult<()> {
+        let guilds = self.snapshots.lock().values().cloned().collect::<Vec<GuildJson>>();
+        let guild_cfg = GuildCfgFile::new(guilds);
+        let guild_string = serde_json::to_string(&guild_cfg)?;
+
+        write_atomic(&self.path, guild_string.as_bytes())?;
+
+        let count = self.save_count.fetch_add(1, Ordering::SeqCst);
+        if count >= BACKUP_EVERY_N_SAVES {
+            if let Err(err) = write_backup(&self.backup_dir, guild_string.as_bytes()) {
+                error!("Error writing guild cache backup: {}", err);
+            }
+            self.save_count.store(0, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// Reads, upgrades and parses a guild cache file, an empty file is treated as an empty cache
+pub fn read_guild_cfg(path: &Path) -> io::Result<GuildCfgFile> {
+    let input = fs::read_to_string(path)?;
+    if input.trim().is_empty() {
+        return Ok(GuildCfgFile::new(vec![]));
+    }
+    let value = serde_json::from_str(&input).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    let mut cfg = migration::migrate(value).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+    cfg.guilds.retain(|guild| {
+        if guild.guild_id == 0 { error!("Dropping guild entry without an id in {}", path.display()); }
+        guild.guild_id != 0
+    });
+    Ok(cfg)
+}
+
+/// Writes to a temporary file first and renames it over the target so a crash never leaves a half written file
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    {
+        let mut tmp_file = File::options().truncate(true).write(true).create(true).open(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn write_backup(backup_dir: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(backup_dir)?;
+    let file_name = format!("{}{}.json", BACKUP_PREFIX, Utc::now().format("%Y%m%d-%H%M%S"));
+    write_atomic(&backup_dir.join(file_name), contents)?;
+
+    let backups = list_backups(backup_dir)?;
+    for old_backup in backups.iter().skip(MAX_BACKUPS) {
+        fs::remove_file(old_backup).ok();
     }
     Ok(())
 }
 
-pub async fn save_guilds_to_disk() {
-    let mut guilds_file = match File::options().truncate(true).write(true).create(true).open(GUILD_JSON_FILE) {
-        Ok(file) => file,
-        Err(err) => { error!("Error opening / creating guild cache: {}", err); return}
+/// Backups sorted newest first, the timestamp in the name sorts lexicographically
+fn list_backups(backup_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut backups = match fs::read_dir(backup_dir) {
+        Ok(dir) => dir.filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(".json")))
+            .collect::<Vec<PathBuf>>(),
+        Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err)
     };
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
 
-    let mut guilds = Vec::new();
-    for guild in GUILD_REGISTRY.lock().await.values() {
-        guilds.push(guild.lock().await.to_json_struct())
+fn read_newest_backup(backup_dir: &Path) -> Option<GuildCfgFile> {
+    let mut candidates = list_backups(backup_dir).unwrap_or_default();
+    candidates.push(PathBuf::from(LEGACY_BACKUP_GUILD_JSON_FILE));
+
+    for path in candidates {
+        match read_guild_cfg(&path) {
+            Ok(cfg) => {
+                log!(Level::Warn, "Recovered guild cache from backup {}", path.display());
+                return Some(cfg);
+            }
+            Err(err) => error!("Skipping backup {}: {}", path.display(), err)
+        }
     }
-    let guild_cfg = GuildCfgFile { guilds };
-    let guild_string = match serde_json::to_string(&guild_cfg) {
-        Ok(string) => string,
-        Err(err) => { error!("Error caching json: {}", err); return }
-    };
+    None
+}
 
-    guilds_file.write(guild_string.as_bytes()).ok();
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
 
-    let count = SAVE_COUNT.fetch_add(1, Ordering::SeqCst);
-    if count >= 4 {
-        match File::options().truncate(true).write(true).create(true).open(BACKUP_GUILD_JSON_FILE) {
-            Ok(mut file) => { file.write(guild_string.as_bytes()).ok(); },
-            Err(_err) => {}
-        };
-        SAVE_COUNT.store(0, Ordering::SeqCst);
+    use crate::storage::GuildStore;
+    use super::{read_guild_cfg, GuildCfgFile, GuildJson, JsonGuildStore, PlayerJson};
+    use super::migration::CURRENT_SCHEMA_VERSION;
+
+    /// `tests/fixtures/guild_cache/v0.json` is laid out the way the bot wrote the file before it was versioned
+    #[test]
+    fn an_unversioned_file_loads() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/guild_cache/v0.json");
+        let cfg = read_guild_cfg(&path).unwrap();
+        let configured = GuildJson { music_channel: Some(11), channel_setup: true, guild_id: 1, ..Default::default() };
+        let unconfigured = GuildJson { guild_id: 2, ..Default::default() };
+        assert_eq!(cfg, GuildCfgFile::new(vec![configured, unconfigured]));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn newer_versions_are_refused() {
+        let path = std::env::temp_dir().join("ayaka-guild-cache-future.json");
+        std::fs::write(&path, format!("{{\"version\": {}, \"guilds\": []}}", CURRENT_SCHEMA_VERSION + 1)).unwrap();
+        assert!(read_guild_cfg(&path).is_err());
+    }
+
+    #[test]
+    fn players_survive_a_save() {
+        let dir = std::env::temp_dir().join(format!("ayaka-json-players-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("guild_cache.json");
+        let guild = GuildJson {
+            guild_id: 1,
+            players: vec![
+                PlayerJson { channel: 2, voice_channel: 3, message: Some(4) },
+                PlayerJson { channel: 5, voice_channel: 6, message: None }
+            ],
+            ..Default::default()
+        };
+
+        let store = JsonGuildStore::new(&path, dir.join("backups"));
+        store.save_guild(guild.clone()).unwrap();
+        store.flush().unwrap();
+        let loaded = JsonGuildStore::new(&path, dir.join("backups")).load_guilds().unwrap();
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(loaded, vec![guild]);
+    }
+}
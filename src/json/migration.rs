@@ -0,0 +1,36 @@
+use serde_json::{Map, Value};
+use crate::json::GuildCfgFile;
+
+/// Bump whenever the layout of [GuildCfgFile] or [crate::json::GuildJson] changes in a way `#[serde(default)]`
+/// can't fill in, and add a step to [MIGRATIONS]
+pub const CURRENT_SCHEMA_VERSION: u32 = 7;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// Steps paired with the version they upgrade a file to, run in order on files older than that version.
+/// Every change up to [CURRENT_SCHEMA_VERSION] only added fields that default, so none are needed yet.
+const MIGRATIONS: &[(u32, Migration)] = &[];
+
+/// Upgrades a parsed cache file of any past version step by step and deserializes it
+pub fn migrate(value: Value) -> Result<GuildCfgFile, String> {
+    let mut root = match value {
+        Value::Object(root) => root,
+        _ => return Err(String::from("Guild cache root is not an object"))
+    };
+
+    // Files written before versioning have no version field
+    let version = match root.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or("Guild cache version is not a number")? as u32
+    };
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(format!("Guild cache is version {} but only up to {} is supported", version, CURRENT_SCHEMA_VERSION));
+    }
+
+    for (_, migration) in MIGRATIONS.iter().filter(|(upgrades_to, _)| *upgrades_to > version) {
+        migration(&mut root)?;
+    }
+    root.insert(String::from("version"), Value::from(CURRENT_SCHEMA_VERSION));
+
+    serde_json::from_value(Value::Object(root)).map_err(|err| err.to_string())
+}
@@ -14,46 +14,63 @@ pub mod interaction;
 pub mod guild;
 pub mod member;
 pub mod json;
-pub mod arcs;
+pub mod storage;
 pub mod troll;
 pub mod details;
 pub mod commands;
+pub mod config;
+pub mod state;
+pub mod error;
+pub mod platform;
 
 
 use std::sync::Arc;
+use std::time::Duration;
 
-#[cfg(not(debug_assertions))]
-use {
-    std::env
-};
-
-use songbird::SerenityInit;
+use songbird::{SerenityInit, Songbird};
 
 use serenity::{
     async_trait,
-    client::{Client, EventHandler, Context},
-    framework::StandardFramework,
+    client::{Client, EventHandler, Context, bridge::gateway::ShardManager},
+    gateway::GatewayError,
     model::{
-        channel::Message,
+        channel::{GuildChannel, Message},
+        guild::{Guild, UnavailableGuild},
+        id::{ChannelId, GuildId, MessageId},
         gateway::Ready,
         application::interaction::Interaction
     },
-    prelude::GatewayIntents
+    prelude::{GatewayIntents, SerenityError}
 };
 
 use crate::{
-    arcs::{CacheAndHttp, register_cache_and_http},
-    interaction::{handle_message},
-    json::{load_guilds_to_cache, save_guilds_to_disk},
+    config::Config,
+    state::{AppState, AppStateKey},
+    platform::SongbirdVoice,
+    interaction::{handle_channel_delete, handle_message, handle_message_delete},
+    storage::{load_guilds_to_cache, open_store, save_guilds_to_disk, save_if_dirty},
+    guild::{connect_stay_channels, disconnect_all, lifecycle},
+    member::{LISTEN_SAMPLE_SECS, sample_listeners},
     commands::{
+        history,
+        play,
+        prefix,
+        prefs,
+        queue_link,
+        replay,
         setup,
+        stats,
+        stay,
     }
 };
 
+use tokio::sync::Mutex;
 use tokio_schedule::Job;
 use tracing::log::{error, Level, log};
 
 
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Handler;
 
 #[macro_use]
@@ -62,38 +79,97 @@ extern crate lazy_static;
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if !msg.is_own(&ctx) {
+        if !msg.is_own(&ctx) && AppState::from_context(&ctx).await.config.features.music_channel {
             handle_message(ctx, msg).await;
         }
     }
 
+    async fn message_delete(&self, ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId, guild_id: Option<GuildId>) {
+        if let Some(guild_id) = guild_id && AppState::from_context(&ctx).await.config.features.music_channel {
+            handle_message_delete(ctx, guild_id, channel_id, vec![deleted_message_id]).await;
+        }
+    }
+
+    async fn message_delete_bulk(&self, ctx: Context, channel_id: ChannelId, deleted_message_ids: Vec<MessageId>, guild_id: Option<GuildId>) {
+        if let Some(guild_id) = guild_id && AppState::from_context(&ctx).await.config.features.music_channel {
+            handle_message_delete(ctx, guild_id, channel_id, deleted_message_ids).await;
+        }
+    }
+
+    async fn channel_delete(&self, ctx: Context, channel: &GuildChannel) {
+        handle_channel_delete(ctx, channel.guild_id, channel.id).await;
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        if is_new {
+            lifecycle::guild_joined(ctx, guild).await;
+        }
+    }
+
+    async fn guild_delete(&self, ctx: Context, incomplete: UnavailableGuild, _full: Option<Guild>) {
+        lifecycle::guild_left(ctx, incomplete).await;
+    }
+
     async fn ready(&self, ctx: Context, ready: Ready) {
-        register_cache_and_http(Arc::new(CacheAndHttp { cache: ctx.cache.clone(), http: ctx.http.clone(), shard: Arc::new(ctx.shard.clone()) })).await;
-        log!(Level::Info, "{} is connected! Beginning disk load", ready.user.name);
+        let state = AppState::from_context(&ctx).await;
+        log!(Level::Info, "{} is connected!", ready.user.name);
+
+        // Ready fires again after every new session, reloading then would replace the running queues with the saved ones
+        if state.begin_loading() {
+            match load_guilds_to_cache(&ctx, &state).await {
+                Ok(_) => log!(Level::Info, "Successfully loaded guilds from disk"),
+                Err(err) => {
+                    panic!("Unable to load guilds from disk due to: {} Aborting.", err)
+                }
+            }
 
-        match load_guilds_to_cache().await {
-            Ok(_) => log!(Level::Info, "Successfully loaded guilds from disk"),
-            Err(err) => {
-                panic!("Unable to load guilds from disk due to: {} Aborting.", err)
+            if state.config.features.stay_connected {
+                connect_stay_channels(&state);
             }
         }
 
-        match commands::register_commands(&ctx.http).await {
+        match commands::register_commands(&ctx.http, &state.config).await {
             Ok(_) => log!(Level::Info, "Commands successfully registered"),
             Err(err) => error!("Error registering commands {}", err)
         }
 
 
-        let future = tokio_schedule::every(5).seconds().perform(|| async { save_guilds_to_disk().await});
-        tokio::spawn(future);
+        let save_state = state.clone();
+        let save = tokio_schedule::every(state.config.save_interval as u32).seconds().perform(move || {
+            let state = save_state.clone();
+            async move { save_if_dirty(&state).await }
+        });
+        let mut jobs = vec![tokio::spawn(save)];
+        if state.config.features.member_tracking {
+            let member_state = state.clone();
+            let sample = tokio_schedule::every(LISTEN_SAMPLE_SECS as u32).seconds().perform(move || {
+                let state = member_state.clone();
+                async move { sample_listeners(&state) }
+            });
+            jobs.push(tokio::spawn(sample));
+        }
+        // Ready fires again after a reconnect, only one set of jobs should be running
+        for previous in std::mem::replace(&mut *state.scheduler.lock(), jobs) {
+            previous.abort();
+        }
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-        if let Interaction::ApplicationCommand(command) = interaction {
-            match command.data.name.as_str() {
+        match interaction {
+            Interaction::ApplicationCommand(command) => match command.data.name.as_str() {
+                play::PLAY_CMD_NAME => play::execute(ctx, command).await,
+                queue_link::QUEUE_LINK_CMD_NAME => queue_link::execute(ctx, command).await,
+                prefs::PREFS_CMD_NAME => prefs::execute(ctx, command).await,
+                history::HISTORY_CMD_NAME => history::execute(ctx, command).await,
+                replay::REPLAY_CMD_NAME => replay::execute(ctx, command).await,
                 setup::SETUP_CMD_NAME => setup::execute(ctx, command).await,
+                stay::STAY_CMD_NAME => stay::execute(ctx, command).await,
+                stats::STATS_CMD_NAME => stats::execute(ctx, command).await,
                 _ => {}
-            };
+            },
+            Interaction::Autocomplete(autocomplete) if autocomplete.data.name == play::PLAY_CMD_NAME =>
+                play::autocomplete(ctx, autocomplete).await,
+            _ => {}
         }
     }
 }
@@ -102,45 +178,73 @@ impl EventHandler for Handler {
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    // Configure the client with your Discord bot token in the environment.
-
-    let mut token = String::new();
-    let mut prefix = "~";
-    cfg_if::cfg_if! {
-        if #[cfg(debug_assertions)] {
-            token = "MjAwOTkyNTc3MTc5MjIyMDE2.GNJAcw.DoztZ5WLN9QpOVRhDZIRy6Q5ogYFQCCsEKVSFA".to_string();
-            prefix = "e!";
-        } else {
-            token = match env::var("bot_token") {
-                Ok(token) => token,
-                Err(_) => {
-                    panic!("Unable to recover main api key");
-                }
-            };
+    let config = match Config::load() {
+        Ok(loaded) => Arc::new(loaded),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
         }
     };
 
-    let framework = StandardFramework::new()
-        .configure(|c| c
-            .prefix(prefix)
-            .allow_dm(false));
+    let intents = config.gateway_intents().expect("Intents are checked when the config is validated");
+    let privileged = intents & GatewayIntents::privileged();
+    log!(Level::Info, "Requesting gateway intents {:?}", intents);
+    if !privileged.is_empty() {
+        log!(Level::Info, "Privileged intents {:?} must be enabled for this bot in the Discord developer portal", privileged);
+    }
 
+    let mut builder = Client::builder(&config.token, intents)
+        .event_handler(Handler);
 
-    let intents = GatewayIntents::all();
+    if config.features.prefix_commands {
+        builder = builder.framework(prefix::framework());
+    }
 
-    let mut client = Client::builder(&token, intents)
-        .event_handler(Handler)
-        .framework(framework)
-        .register_songbird()
+    let songbird = Songbird::serenity();
+    let mut client = builder
+        .register_songbird_with(songbird.clone())
         .await
         .expect("Err creating client");
 
+    let cache = client.cache_and_http.cache.clone();
+    let state = Arc::new(AppState::new(
+        config.clone(),
+        cache.clone(),
+        client.cache_and_http.http.clone(),
+        open_store(&config.storage),
+        Arc::new(SongbirdVoice::new(songbird, cache))
+    ));
+    client.data.write().await.insert::<AppStateKey>(state.clone());
+
 
 
+    let shard_manager = client.shard_manager.clone();
     tokio::spawn(async move {
-        let _ = client.start().await.map_err(|why| println!("Client ended: {:?}", why));
+        match client.start().await {
+            Ok(_) => {}
+            Err(SerenityError::Gateway(GatewayError::DisallowedGatewayIntents)) => {
+                error!("Discord refused the privileged intents {:?}. Enable them in the developer portal \
+                    or turn off the features that need them in the config file", privileged);
+                std::process::exit(1);
+            }
+            Err(why) => println!("Client ended: {:?}", why)
+        }
     });
 
     tokio::signal::ctrl_c().await.ok();
     println!("Received Ctrl-C, shutting down.");
-}
\ No newline at end of file
+    shutdown(&state, shard_manager).await;
+}
+
+/// Stops the scheduler first so the final save can't race a periodic one
+async fn shutdown(state: &Arc<AppState>, shard_manager: Arc<Mutex<ShardManager>>) {
+    state.begin_shutdown();
+    save_guilds_to_disk(state).await;
+
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, disconnect_all(state)).await.is_err() {
+        error!("Timed out leaving voice channels, shutting down anyway");
+    }
+
+    shard_manager.lock().await.shutdown_all().await;
+    log!(Level::Info, "Shutdown complete");
+}
@@ -1,4 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serenity::model::id::UserId;
+use songbird::input::Metadata;
+
+use crate::json::MemberJson;
+use crate::state::AppState;
+
+/// How often listeners are counted, each count adds this many seconds to their listening time
+pub const LISTEN_SAMPLE_SECS: u64 = 60;
+/// Tracks and artists remembered per member, the least played is forgotten to make room
+const MAX_TOP_ENTRIES: usize = 50;
+
+/// Preferences of every member of a guild, shared with the guild's players so they apply as tracks start
+pub type SharedPreferences = Arc<RwLock<HashMap<UserId, MemberPreferences>>>;
+
+/// Listening statistics of every member of a guild, only filled in with `features.member_tracking`,
+/// and the preferences members chose for themselves
 #[derive(Default, Debug)]
 pub struct MemberManager {
+    members: HashMap<UserId, MemberStats>,
+    preferences: SharedPreferences
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MemberPreferences {
+    /// Percent, applied to the tracks this member requested
+    pub volume: Option<u32>,
+    /// DM this member when a track they requested starts
+    pub dm_now_playing: bool,
+    /// Urls this member never wants replayed without asking, see [crate::music::music_manager::MusicManager::change_track]
+    pub blocked: Vec<String>
+}
+
+impl MemberPreferences {
+    pub fn volume_scale(&self) -> f32 {
+        self.volume.map(|volume| volume as f32 / 100.0).unwrap_or(1.0)
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct MemberStats {
+    pub tracks_requested: u64,
+    pub listening_secs: u64,
+    /// Times each title was requested
+    pub tracks: HashMap<String, u64>,
+    pub artists: HashMap<String, u64>
+}
+
+impl MemberStats {
+    /// Most requested first, ties in name order so the list doesn't shuffle between calls
+    pub fn top_tracks(&self, limit: usize) -> Vec<(&str, u64)> {
+        top(&self.tracks, limit)
+    }
+
+    pub fn top_artists(&self, limit: usize) -> Vec<(&str, u64)> {
+        top(&self.artists, limit)
+    }
+}
+
+impl MemberManager {
+    pub fn record_request(&mut self, user_id: UserId, metadata: &Metadata) {
+        let stats = self.members.entry(user_id).or_default();
+        stats.tracks_requested += 1;
+        if let Some(title) = &metadata.title {
+            count(&mut stats.tracks, title);
+        }
+        // Youtube rarely fills in the artist, the uploading channel is the next best thing
+        if let Some(artist) = metadata.artist.as_ref().or(metadata.channel.as_ref()) {
+            count(&mut stats.artists, artist);
+        }
+    }
+
+    pub fn record_listening(&mut self, user_id: UserId, secs: u64) {
+        self.members.entry(user_id).or_default().listening_secs += secs;
+    }
+
+    /// Handed to every player of the guild
+    pub fn shared_preferences(&self) -> SharedPreferences {
+        self.preferences.clone()
+    }
+
+    pub fn preferences(&self, user_id: UserId) -> MemberPreferences {
+        self.preferences.read().get(&user_id).cloned().unwrap_or_default()
+    }
+
+    pub fn update_preferences(&mut self, user_id: UserId, update: impl FnOnce(&mut MemberPreferences)) {
+        let mut preferences = self.preferences.write();
+        let member = preferences.entry(user_id).or_default();
+        update(member);
+        if *member == MemberPreferences::default() {
+            preferences.remove(&user_id);
+        }
+    }
+
+    pub fn stats(&self, user_id: UserId) -> Option<&MemberStats> {
+        self.members.get(&user_id)
+    }
+
+    /// Members ranked by `key`, highest first, members at zero are left out
+    pub fn leaderboard(&self, key: impl Fn(&MemberStats) -> u64, limit: usize) -> Vec<(UserId, u64)> {
+        let mut ranked = self.members.iter()
+            .map(|(user_id, stats)| (*user_id, key(stats)))
+            .filter(|(_, value)| *value > 0)
+            .collect::<Vec<(UserId, u64)>>();
+        ranked.sort_by(|(a_id, a_value), (b_id, b_value)| b_value.cmp(a_value).then(a_id.cmp(b_id)));
+        ranked.truncate(limit);
+        ranked
+    }
+
+    /// Requests of every member added together
+    pub fn guild_top_tracks(&self, limit: usize) -> Vec<(String, u64)> {
+        owned(top(&sum(self.members.values().map(|stats| &stats.tracks)), limit))
+    }
+
+    pub fn guild_top_artists(&self, limit: usize) -> Vec<(String, u64)> {
+        owned(top(&sum(self.members.values().map(|stats| &stats.artists)), limit))
+    }
+
+    pub fn from_json(members: &[MemberJson]) -> MemberManager {
+        let preferences = members.iter()
+            .map(|member| (UserId(member.user_id), MemberPreferences {
+                volume: member.volume,
+                dm_now_playing: member.dm_now_playing,
+                blocked: member.blocked.clone()
+            }))
+            .filter(|(_, preferences)| *preferences != MemberPreferences::default())
+            .collect();
+        MemberManager {
+            members: members.iter().map(|member| (UserId(member.user_id), MemberStats {
+                tracks_requested: member.tracks_requested,
+                listening_secs: member.listening_secs,
+                tracks: member.tracks.iter().cloned().collect(),
+                artists: member.artists.iter().cloned().collect()
+            })).collect(),
+            preferences: Arc::new(RwLock::new(preferences))
+        }
+    }
+
+    /// Members with only stats or only preferences get an entry too
+    pub fn to_json(&self) -> Vec<MemberJson> {
+        let preferences = self.preferences.read();
+        let user_ids = self.members.keys().chain(preferences.keys()).collect::<HashSet<&UserId>>();
+        let mut members = user_ids.into_iter().map(|user_id| {
+            let stats = self.members.get(user_id).cloned().unwrap_or_default();
+            let member_preferences = preferences.get(user_id).cloned().unwrap_or_default();
+            MemberJson {
+                user_id: user_id.0,
+                tracks_requested: stats.tracks_requested,
+                listening_secs: stats.listening_secs,
+                tracks: owned(stats.top_tracks(MAX_TOP_ENTRIES)),
+                artists: owned(stats.top_artists(MAX_TOP_ENTRIES)),
+                volume: member_preferences.volume,
+                dm_now_playing: member_preferences.dm_now_playing,
+                blocked: member_preferences.blocked
+            }
+        }).collect::<Vec<MemberJson>>();
+        // Keeps the saved file stable so unchanged guilds don't look changed
+        members.sort_by_key(|member| member.user_id);
+        members
+    }
+}
+
+fn count(counts: &mut HashMap<String, u64>, name: &str) {
+    if !counts.contains_key(name) && counts.len() >= MAX_TOP_ENTRIES {
+        if let Some(least) = top(counts, usize::MAX).last().map(|(name, _)| name.to_string()) {
+            counts.remove(&least);
+        }
+    }
+    *counts.entry(name.to_string()).or_default() += 1;
+}
+
+fn sum<'a>(all_counts: impl Iterator<Item = &'a HashMap<String, u64>>) -> HashMap<String, u64> {
+    let mut total = HashMap::new();
+    for counts in all_counts {
+        for (name, count) in counts {
+            *total.entry(name.clone()).or_default() += count;
+        }
+    }
+    total
+}
+
+fn top(counts: &HashMap<String, u64>, limit: usize) -> Vec<(&str, u64)> {
+    let mut top = counts.iter().map(|(name, count)| (name.as_str(), *count)).collect::<Vec<(&str, u64)>>();
+    top.sort_by(|(a_name, a_count), (b_name, b_count)| b_count.cmp(a_count).then(a_name.cmp(b_name)));
+    top.truncate(limit);
+    top
+}
+
+fn owned(entries: Vec<(&str, u64)>) -> Vec<(String, u64)> {
+    entries.into_iter().map(|(name, count)| (name.to_string(), count)).collect()
+}
+
+/// Adds [LISTEN_SAMPLE_SECS] to everyone listening along in a voice channel the bot is playing in.
+/// Deafened members and bots aren't counted.
+pub fn sample_listeners(state: &Arc<AppState>) {
+    for handle in state.all_guilds() {
+        let state = state.clone();
+        handle.cast(move |guild| Box::pin(async move {
+            let channel_id = match guild.player_mut(guild.active) {
+                Some((music, _)) if music.is_playing => music.current_channel().await,
+                _ => None
+            };
+            let listeners = match channel_id {
+                None => return,
+                Some(channel_id) => state.voice.listeners(guild.id, channel_id)
+            };
+            if listeners.is_empty() { return; }
+
+            for user_id in listeners {
+                guild.member.record_listening(user_id, LISTEN_SAMPLE_SECS);
+            }
+            guild.mark_dirty();
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::id::UserId;
+    use songbird::input::Metadata;
+
+    use crate::member::{MAX_TOP_ENTRIES, MemberManager};
+
+    fn track(title: &str, artist: Option<&str>, channel: &str) -> Metadata {
+        Metadata {
+            title: Some(title.to_string()),
+            artist: artist.map(String::from),
+            channel: Some(channel.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requests_count_tracks_and_artists() {
+        let mut members = MemberManager::default();
+        let user = UserId(1);
+        members.record_request(user, &track("a", Some("artist"), "uploader"));
+        members.record_request(user, &track("b", None, "uploader"));
+        members.record_request(user, &track("a", Some("artist"), "uploader"));
+
+        let stats = members.stats(user).unwrap();
+        assert_eq!(stats.tracks_requested, 3);
+        assert_eq!(stats.top_tracks(10), vec![("a", 2), ("b", 1)]);
+        // The uploading channel stands in when there is no artist
+        assert_eq!(stats.top_artists(10), vec![("artist", 2), ("uploader", 1)]);
+        assert!(members.stats(UserId(2)).is_none());
+    }
+
+    #[test]
+    fn full_counts_forget_the_least_requested() {
+        let mut members = MemberManager::default();
+        let user = UserId(1);
+        members.record_request(user, &track("favourite", None, "uploader"));
+        members.record_request(user, &track("favourite", None, "uploader"));
+        for i in 0..MAX_TOP_ENTRIES {
+            members.record_request(user, &track(&format!("track {:02}", i), None, "uploader"));
+        }
+
+        let top = members.stats(user).unwrap().top_tracks(usize::MAX);
+        assert_eq!(top.len(), MAX_TOP_ENTRIES);
+        assert_eq!(top[0], ("favourite", 2), "the most requested track was forgotten");
+    }
+
+    #[test]
+    fn leaderboards_rank_members_and_add_up_the_guild() {
+        let mut members = MemberManager::default();
+        let (first, second, idle) = (UserId(1), UserId(2), UserId(3));
+        members.record_listening(first, 120);
+        members.record_listening(second, 60);
+        members.record_listening(second, 120);
+        members.record_request(first, &track("a", None, "uploader"));
+        members.record_request(second, &track("a", None, "uploader"));
+        members.record_request(second, &track("b", None, "uploader"));
+        members.record_listening(idle, 0);
+
+        assert_eq!(members.leaderboard(|stats| stats.listening_secs, 10), vec![(second, 180), (first, 120)]);
+        assert_eq!(members.leaderboard(|stats| stats.tracks_requested, 1), vec![(second, 2)]);
+        assert_eq!(members.guild_top_tracks(10), vec![(String::from("a"), 2), (String::from("b"), 1)]);
+        assert_eq!(members.guild_top_artists(10), vec![(String::from("uploader"), 3)]);
+    }
+
+    #[test]
+    fn stats_and_preferences_survive_a_save() {
+        let mut members = MemberManager::default();
+        members.record_request(UserId(1), &track("a", Some("artist"), "uploader"));
+        members.update_preferences(UserId(2), |preferences| preferences.volume = Some(50));
 
-}
\ No newline at end of file
+        let json = members.to_json();
+        assert_eq!(json.iter().map(|member| member.user_id).collect::<Vec<u64>>(), vec![1, 2]);
+        let restored = MemberManager::from_json(&json);
+        assert_eq!(restored.to_json(), json);
+        assert_eq!(restored.preferences(UserId(2)).volume, Some(50));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use chrono::Utc;
+use parking_lot::RwLock;
+use serenity::model::id::UserId;
+use songbird::input::Metadata;
+
+use crate::json::HistoryJson;
+
+/// Plays kept per guild, the oldest is forgotten first
+pub const MAX_PLAY_HISTORY: usize = 500;
+/// How much one extra play is worth against how well a title matches
+const FREQUENCY_WEIGHT: u32 = 10;
+
+/// History of a guild, shared with its players so every player records into the same log
+pub type SharedHistory = Arc<RwLock<PlayHistory>>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlayedTrack {
+    /// Counts up from 1 for the guild's first play and never changes, so it still names the same play after older ones are forgotten
+    pub number: u64,
+    /// Unix timestamp in seconds
+    pub played_at: i64,
+    pub requester: Option<UserId>,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub duration_secs: Option<u64>
+}
+
+/// A track /play suggests, only plays with a link can be suggested
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    pub title: String,
+    pub url: String
+}
+
+/// Unlike the queue's own history this outlives [crate::music::queue::MAX_QUEUE_HISTORY] and restarts
+#[derive(Debug, Default)]
+pub struct PlayHistory {
+    plays: VecDeque<PlayedTrack>
+}
+
+impl PlayHistory {
+    pub fn record(&mut self, requester: Option<UserId>, metadata: &Metadata) {
+        let number = self.plays.back().map_or(1, |last| last.number + 1);
+        if self.plays.len() >= MAX_PLAY_HISTORY {
+            self.plays.pop_front();
+        }
+        self.plays.push_back(PlayedTrack {
+            number,
+            played_at: Utc::now().timestamp(),
+            requester,
+            title: metadata.title.clone(),
+            url: metadata.source_url.clone(),
+            duration_secs: metadata.duration.map(|duration| duration.as_secs())
+        });
+    }
+
+    /// Newest first
+    pub fn iter(&self) -> impl Iterator<Item = &PlayedTrack> {
+        self.plays.iter().rev()
+    }
+
+    /// The play with [PlayedTrack::number], the numbering `/history` shows
+    pub fn get(&self, number: u64) -> Option<&PlayedTrack> {
+        let first = self.plays.front()?.number;
+        self.plays.get(number.checked_sub(first)? as usize)
+    }
+
+    /// Plays saved before they were numbered are numbered in order, after any numbered ones before them
+    pub fn from_json(history: &[HistoryJson]) -> PlayHistory {
+        let skip = history.len().saturating_sub(MAX_PLAY_HISTORY);
+        let mut previous = 0;
+        PlayHistory {
+            plays: history.iter().skip(skip).map(|play| {
+                previous = if play.number > previous { play.number } else { previous + 1 };
+                PlayedTrack {
+                    number: previous,
+                    played_at: play.played_at,
+                    requester: play.requester.map(UserId),
+                    title: play.title.clone(),
+                    url: play.url.clone(),
+                    duration_secs: play.duration_secs
+                }
+            }).collect()
+        }
+    }
+
+    /// Oldest first, the order plays are appended in
+    pub fn to_json(&self) -> Vec<HistoryJson> {
+        self.plays.iter().map(PlayedTrack::to_json).collect()
+    }
+}
+
+impl PlayedTrack {
+    pub fn to_json(&self) -> HistoryJson {
+        HistoryJson {
+            number: self.number,
+            played_at: self.played_at,
+            requester: self.requester.map(|user_id| user_id.0),
+            title: self.title.clone(),
+            url: self.url.clone(),
+            duration_secs: self.duration_secs
+        }
+    }
+}
+
+/// Distinct tracks matching `query`, ranked by how often they were played and how well they match.
+/// `plays` should be newest first, ties go to the most recent play.
+pub fn suggest<'a>(query: &str, plays: impl Iterator<Item = &'a PlayedTrack>, limit: usize) -> Vec<Suggestion> {
+    let query = query.trim().to_lowercase();
+    // (track, times played, order of first appearance)
+    let mut counted: Vec<(Suggestion, u32, usize)> = vec![];
+    for play in plays {
+        let url = match &play.url {
+            None => continue,
+            Some(url) => url
+        };
+        match counted.iter_mut().find(|(counted, _, _)| counted.url == *url) {
+            Some((_, count, _)) => *count += 1,
+            None => {
+                let title = play.title.clone().unwrap_or_else(|| url.clone());
+                counted.push((Suggestion { title, url: url.clone() }, 1, counted.len()))
+            }
+        }
+    }
+
+    let mut ranked = counted.into_iter()
+        .filter_map(|(track, count, order)| {
+            let score = match_score(&query, &track.title.to_lowercase())?;
+            Some((track, score + count * FREQUENCY_WEIGHT, order))
+        })
+        .collect::<Vec<(Suggestion, u32, usize)>>();
+    ranked.sort_by(|(_, a_score, a_order), (_, b_score, b_order)| b_score.cmp(a_score).then(a_order.cmp(b_order)));
+    ranked.into_iter().take(limit).map(|(track, _, _)| track).collect()
+}
+
+/// None when `title` doesn't contain every character of `query` in order.
+/// A substring beats a scattered match, and matches closer to the start beat later ones.
+fn match_score(query: &str, title: &str) -> Option<u32> {
+    if query.is_empty() { return Some(0); }
+    if let Some(position) = title.find(query) {
+        return Some(100u32.saturating_sub(position as u32).max(50));
+    }
+
+    let mut gaps = 0u32;
+    let mut title_chars = title.chars();
+    for wanted in query.chars() {
+        loop {
+            match title_chars.next() {
+                None => return None,
+                Some(c) if c == wanted => break,
+                Some(_) => gaps += 1
+            }
+        }
+    }
+    Some(40u32.saturating_sub(gaps))
+}
+
+#[cfg(test)]
+mod tests {
+    use songbird::input::Metadata;
+
+    use crate::json::HistoryJson;
+    use crate::music::history::{MAX_PLAY_HISTORY, PlayHistory};
+
+    fn titled(title: &str) -> Metadata {
+        Metadata { title: Some(title.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn numbers_survive_older_plays_being_forgotten() {
+        let mut history = PlayHistory::default();
+        history.record(None, &titled("first"));
+        history.record(None, &titled("second"));
+        assert_eq!(history.get(2).and_then(|play| play.title.as_deref()), Some("second"));
+
+        for _ in 0..MAX_PLAY_HISTORY {
+            history.record(None, &titled("filler"));
+        }
+        assert!(history.get(1).is_none(), "a forgotten play can still be found");
+        assert_eq!(history.iter().next().map(|play| play.number), Some(MAX_PLAY_HISTORY as u64 + 2));
+        assert_eq!(history.get(MAX_PLAY_HISTORY as u64 + 2).map(|play| play.number), Some(MAX_PLAY_HISTORY as u64 + 2));
+    }
+
+    #[test]
+    fn unnumbered_plays_are_numbered_on_load() {
+        let play = |number| HistoryJson { number, ..Default::default() };
+        let history = PlayHistory::from_json(&[play(0), play(0), play(7), play(0)]);
+        assert_eq!(history.iter().map(|play| play.number).collect::<Vec<u64>>(), vec![8, 7, 2, 1]);
+        assert_eq!(history.to_json().iter().map(|play| play.number).collect::<Vec<u64>>(), vec![1, 2, 7, 8]);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod music_manager;
 pub mod discord;
-pub mod state;
\ No newline at end of file
+pub mod state;
+pub mod queue;
+pub mod history;
\ No newline at end of file
@@ -1,39 +1,55 @@
-use tokio::sync::Mutex;
-
-
-
-use std::sync::Arc;
-use rand::Rng;
+use std::collections::HashSet;
+use std::sync::{Arc, Weak};
 use serenity::async_trait;
-use serenity::client::Context;
-use serenity::model::channel::{Message};
-use serenity::model::guild::Guild;
-
-use serenity::model::id::{GuildId};
-use songbird::{Call, Event, EventContext, EventHandler, TrackEvent};
-use songbird::input::{Input, Metadata, Restartable};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use songbird::{CoreEvent, Event, EventContext, EventHandler, TrackEvent};
+use songbird::input::Metadata;
 use tracing::error;
-use crate::guild::GUILD_REGISTRY;
-use crate::music::discord::{get_user_vc, join_guild_channel_from_msg};
+use tracing::log::{Level, log};
+use crate::error::{AyakaError, AyakaResult};
+use crate::guild::player::PlayerId;
+use crate::music::discord::get_user_vc;
+use crate::music::queue::Queue;
+use crate::member::SharedPreferences;
+use crate::music::history::SharedHistory;
 use crate::music::state::{MusicState, QueueAction, QueueItem};
-
-const MAX_QUEUE_HISTORY: usize = 20;
+use crate::platform::{Source, VoiceCall};
+use crate::state::AppState;
 
 unsafe impl Sync for MusicManager {}
 
 #[derive(Debug)]
 pub struct MusicManager {
-    queue: Vec<Restartable>,
-    handler: Option<Arc<Mutex<Call>>>,
-    next_track: usize,
+    queue: Queue<QueuedTrack>,
+    handler: Option<Arc<dyn VoiceCall>>,
     pub is_playing: bool,
     pub guild_id: GuildId,
-    pub looping: bool,
-    pub shuffling: bool
+    pub stay_connected: Option<ChannelId>,
+    pub idle_source: Option<String>,
+    /// Tracks started from the queue, the idle source isn't included
+    playing_url: Option<String>,
+    preferences: SharedPreferences,
+    history: SharedHistory
+}
+
+#[derive(Clone, Debug)]
+struct QueuedTrack {
+    source: Source,
+    url: Option<String>,
+    /// Whoever asked for it, their preferences apply while it plays
+    requester: Option<UserId>,
+    plays: u32
 }
 
+/// Voice events hold a weak reference since the call they are attached to is itself kept alive through [AppState]
 pub struct TrackEndEvent {
-    id: GuildId
+    id: GuildId,
+    state: Weak<AppState>
+}
+
+pub struct DriverDisconnectEvent {
+    id: GuildId,
+    state: Weak<AppState>
 }
 
 
@@ -41,189 +57,241 @@ pub struct TrackEndEvent {
 impl EventHandler for TrackEndEvent {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
         let event = ctx.to_core_event().map(|c| c.into());
-        let registry_lock = GUILD_REGISTRY.lock().await;
-        let guild_manager = registry_lock.get(&self.id)?.clone();
-        let mut guild_lock = guild_manager.lock().await;
-        let metadata = guild_lock.music.change_track(QueueAction::SoftNext).await;
-        if let Some(interaction) = &mut guild_lock.interaction {
-            interaction.update_message(metadata, QueueAction::HardNext).await;
-        }
+        let state = self.state.upgrade()?;
+        state.guild(self.id)?.cast(move |guild| Box::pin(async move {
+            let finished = match guild.player_mut(guild.active) {
+                None => return,
+                Some((music, interaction)) => {
+                    let metadata = music.change_track(&state, QueueAction::SoftNext).await;
+                    if let Some(interaction) = interaction {
+                        interaction.update_message(&state, metadata, QueueAction::HardNext).await;
+                    }
+                    !music.is_playing
+                }
+            };
+            // A bound player ran out of songs, 24/7 mode gets the connection back
+            if finished && guild.active != PlayerId::Default {
+                if let Err(err) = guild.resume_stay(&state).await {
+                    error!("Unable to return to 24/7 channel in {}: {}", guild.id, err);
+                }
+            }
+        }));
         event
     }
 }
 
+#[async_trait]
+impl EventHandler for DriverDisconnectEvent {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let state = self.state.upgrade()?;
+        if state.is_shutting_down() { return None; }
+        // Rejoining locks the call this event was dispatched from, the actor runs it outside of the driver
+        state.guild(self.id)?.cast(move |guild| Box::pin(async move {
+            if let Some(channel_id) = guild.music.stay_connected {
+                log!(Level::Info, "Voice disconnected in {}, rejoining 24/7 channel {}", guild.id, channel_id);
+                if let Err(err) = guild.resume_stay(&state).await {
+                    error!("Unable to rejoin 24/7 channel {}: {}", channel_id, err);
+                }
+            }
+        }));
+        None
+    }
+}
+
 impl MusicManager {
-    pub fn new_no_async(guild_id: GuildId) -> MusicManager {
+    pub fn new_no_async(guild_id: GuildId, preferences: SharedPreferences, history: SharedHistory) -> MusicManager {
         MusicManager {
-            queue: vec![],
+            queue: Queue::default(),
             handler: None,
-            next_track: 0,
             is_playing: false,
             guild_id,
-            looping: false,
-            shuffling: false
+            stay_connected: None,
+            idle_source: None,
+            playing_url: None,
+            preferences,
+            history
         }
     }
 
-    pub async fn try_join(&mut self, context: &Context, message: &Message, guild: Option<Guild>) -> Result<(), ()> {
-        let mut new_handler = false;
-        let guild = match guild {
-            None => return Err(()),
-            Some(guild) => guild
-        };
-        let guild_id = guild.id;
-
-        self.handler = match &self.handler {
-            Some(handler) => {
-                let mut opt_handler = Some(handler.clone());
-                let lock = handler.lock().await;
-                let author_vc = get_user_vc(guild, message.author.clone());
-                if let Some(current_channel) = lock.current_channel() {
-                    if let Some(author_channel) = author_vc {
-                        if author_channel.0 != current_channel.0 {
-                            new_handler = true;
-                            opt_handler = join_guild_channel_from_msg(context, message).await.0
-                        }
-                    }
-                } opt_handler
-            }
-            None => {
-                new_handler = true;
-                match join_guild_channel_from_msg(context, message).await.0 {
-                    None => return Err(()),
-                    Some(connection) => Some(connection)
-                }
-            }
-        };
-        if new_handler && let Some(handler) = &self.handler {
-            handler.clone().lock().await.add_global_event(
+    /// Joins a voice channel without needing a message to take the guild and author from
+    pub async fn join_channel(&mut self, state: &Arc<AppState>, channel_id: ChannelId) -> AyakaResult<()> {
+        let handler = state.voice.join(self.guild_id, channel_id).await?;
+
+        let new_handler = !self.handler.as_ref().is_some_and(|current| current.call_id() == handler.call_id());
+        if new_handler {
+            handler.add_global_event(
                 Event::Track(TrackEvent::End),
-                TrackEndEvent {
-                    id: guild_id
-                });
+                Box::new(TrackEndEvent { id: self.guild_id, state: Arc::downgrade(state) })).await;
+            handler.add_global_event(
+                Event::Core(CoreEvent::DriverDisconnect),
+                Box::new(DriverDisconnectEvent { id: self.guild_id, state: Arc::downgrade(state) })).await;
         }
+        self.handler = Some(handler);
         Ok(())
     }
 
-    fn neaten_queue(&mut self) {
-        if self.next_track > MAX_QUEUE_HISTORY {
-            self.queue.remove(0);
-            self.next_track = self.next_track.saturating_sub(1);
+    /// Joins the author's voice channel unless already connected to one they aren't in
+    pub async fn try_join(&mut self, state: &Arc<AppState>, author: UserId) -> AyakaResult<()> {
+        let guild = state.cache.guild(self.guild_id).ok_or(AyakaError::NotInGuild)?;
+        let author_vc = get_user_vc(&guild, author);
+
+        if let Some(handler) = &self.handler {
+            match (handler.current_channel().await, author_vc) {
+                (Some(current_channel), Some(author_channel)) if current_channel != author_channel => {}
+                _ => return Ok(())
+            }
         }
+        self.join_channel(state, author_vc.ok_or(AyakaError::NotInVoice)?).await
     }
 
-    pub async fn search_and_queue(&mut self, name: String) {
-        self.neaten_queue();
+    /// Gives up the voice connection without leaving so another player can take it over
+    pub fn release(&mut self) -> Option<Arc<dyn VoiceCall>> {
+        self.is_playing = false;
+        self.handler.take()
+    }
 
-        self.queue.push(
-            match Restartable::ytdl_search(name, true).await {
-                Ok(source) => source,
-                Err(err) => {
-                    error!("Error creating music source: {}", err);
-                    return;
-                }
-            }
-        );
+    /// Takes over a connection released by another player, its events are already registered
+    pub fn adopt(&mut self, handler: Option<Arc<dyn VoiceCall>>) {
+        if handler.is_some() {
+            self.handler = handler;
+        }
     }
 
-    pub async fn queue(&mut self, url: String) {
-        self.neaten_queue();
+    /// Url of the track that is playing, None between tracks and for the idle source
+    pub fn current_url(&self) -> Option<String> {
+        if !self.is_playing { return None; }
+        self.playing_url.clone()
+    }
 
-        self.queue.push(
-            match Restartable::ytdl(url, true).await {
-                Ok(source) => source,
-                Err(err) => {
-                    error!("Error creating music source: {}", err);
-                    return;
-                }
-            }
-        );
+    pub async fn current_channel(&self) -> Option<ChannelId> {
+        self.handler.as_ref()?.current_channel().await
+    }
+
+    /// Disconnects from voice, 24/7 mode is left configured so the next start rejoins
+    pub async fn leave(&mut self, state: &AppState) -> AyakaResult<()> {
+        if self.handler.take().is_none() { return Ok(()); }
+        self.is_playing = false;
+        state.voice.leave(self.guild_id).await
+    }
+
+    /// Returns what was queued so the request can be credited to whoever made it
+    pub fn push(&mut self, source: Source, requester: Option<UserId>) -> Metadata {
+        let metadata = source.metadata.clone();
+        self.queue.push(QueuedTrack { source, url: metadata.source_url.clone(), requester, plays: 0 });
+        metadata
     }
 
     pub fn cut_line(&mut self, target: usize) {
-        let item = self.queue.remove(target);
-        self.queue.insert(self.next_track, item);
+        self.queue.cut_line(target);
     }
 
     pub fn toggle_loop(&mut self) -> MusicState {
-        self.looping = !self.looping;
-        if self.looping {
-            self.queue = self.queue.split_off(self.next_track.saturating_sub(1));
-            self.next_track = 0;
-        }
+        self.queue.toggle_loop();
         self.get_state(None)
     }
 
     pub fn toggle_shuffle(&mut self) -> MusicState {
-        self.shuffling = !self.shuffling;
+        self.queue.toggle_shuffle();
         self.get_state(None)
     }
 
-    pub async fn stop_music(&mut self) -> MusicState {
+    pub async fn stop_music(&mut self, state: &AppState) -> MusicState {
         self.queue.clear();
-        self.change_track(QueueAction::HardNext).await
+        self.change_track(state, QueueAction::HardNext).await
     }
 
-    pub async fn change_track(&mut self, action: QueueAction) -> MusicState {
-        let handle = match &self.handler {
+    /// Plays the track `action` leads to with its requester's preferences.
+    /// When the bot moves on by itself, tracks it would replay are skipped if someone listening blocked them.
+    pub async fn change_track(&mut self, state: &AppState, action: QueueAction) -> MusicState {
+        let handler = match &self.handler {
             None => return self.get_state(None),
-            Some(handle) => handle.clone()
+            Some(handler) => handler.clone()
         };
-        let mut handler_lock = handle.lock().await;
 
-        match action {
-            QueueAction::HardNext | QueueAction::SelectedNext => {
-                if self.is_playing { handler_lock.stop(); }
-            }
-            QueueAction::Previous => { self.next_track = self.next_track.saturating_sub(2); }
-            _ => {}
+        if matches!(action, QueueAction::HardNext | QueueAction::SelectedNext) && self.is_playing {
+            handler.stop().await;
         }
 
-        if self.shuffling && action != QueueAction::SelectedNext && !self.queue.is_empty() {
-            let mut next_track = rand::thread_rng().gen_range(0..self.queue.len());
-            while next_track == self.next_track.saturating_sub(1) && self.next_track.saturating_sub(1) > 0 {
-                next_track = rand::thread_rng().gen_range(0..self.queue.len());
+        let blocked = match (action, handler.current_channel().await) {
+            (QueueAction::SoftNext, Some(channel_id)) => self.blocked_by_listeners(state, channel_id),
+            _ => HashSet::new()
+        };
+        // Every track gets one chance, so a queue where everything is blocked still ends
+        let mut skips_left = self.queue.len();
+        let next = loop {
+            match self.queue.advance(action) {
+                None => break None,
+                Some(track) => {
+                    let is_blocked = track.plays > 0 && track.url.as_ref().is_some_and(|url| blocked.contains(url));
+                    if !is_blocked || skips_left == 0 {
+                        track.plays += 1;
+                        break Some(track.clone());
+                    }
+                    skips_left -= 1;
+                }
             }
-            self.next_track = next_track;
-        }
-
-        if self.next_track >= self.queue.len() && self.looping {
-            self.next_track = 0
-        }
+        };
 
-        let track = match self.queue.get(self.next_track) {
-            Some(track) => track.clone(),
+        let track = match next {
+            Some(track) => track,
             None => {
                 self.is_playing = false;
+                if self.stay_connected.is_some() && let Some(idle_source) = &self.idle_source {
+                    match state.sources.resolve(idle_source.clone()).await {
+                        Ok(source) => handler.play_only(source, 1.0).await,
+                        Err(err) => error!("Error creating idle music source: {}", err)
+                    };
+                }
                 return MusicState {
                     metadata: None,
                     queue_names: vec![],
-                    looping: self.looping,
-                    shuffling: self.shuffling
+                    looping: self.queue.looping(),
+                    shuffling: self.queue.shuffling()
                 }
             }
         };
 
-        self.next_track += 1;
         self.is_playing = true;
-        let input: Input = track.into();
-        let metadata = input.metadata.clone();
-
-
-        handler_lock.play_only_source(input);
+        let preferences = track.requester
+            .and_then(|requester| self.preferences.read().get(&requester).cloned())
+            .unwrap_or_default();
+        self.playing_url = track.url.clone();
+        let metadata = Box::new(track.source.metadata.clone());
+        self.history.write().record(track.requester, &metadata);
+        state.dirty.mark(self.guild_id);
+
+        handler.play_only(track.source, preferences.volume_scale()).await;
+        if let Some(requester) = track.requester && preferences.dm_now_playing && track.plays == 1 {
+            self.send_now_playing(state, requester, &metadata);
+        }
         self.get_state(Some(metadata))
     }
 
-    pub fn get_items_in_queue(&self) -> Vec<QueueItem> {
-        let skip_amount = if self.shuffling { 0 } else { self.next_track };
+    fn blocked_by_listeners(&self, state: &AppState, channel_id: ChannelId) -> HashSet<String> {
+        let preferences = self.preferences.read();
+        state.voice.listeners(self.guild_id, channel_id).iter()
+            .filter_map(|user_id| preferences.get(user_id))
+            .flat_map(|member| member.blocked.iter().cloned())
+            .collect()
+    }
 
+    /// Sent in the background, a member with DMs closed shouldn't hold up the queue
+    fn send_now_playing(&self, state: &AppState, requester: UserId, metadata: &Metadata) {
+        let guild_name = state.cache.guild(self.guild_id).map(|guild| guild.name).unwrap_or_default();
+        let content = format!("Now playing **{}** in {}", metadata.title.clone().unwrap_or_default(), guild_name);
+        let messenger = state.messenger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = messenger.dm(requester, content).await {
+                log!(Level::Info, "Unable to DM {} about their track: {}", requester, err);
+            }
+        });
+    }
 
-        self.queue.iter()
-            .enumerate()
-            .skip(skip_amount)
+    pub fn get_items_in_queue(&self) -> Vec<QueueItem> {
+        self.queue.upcoming()
             .map(|(i, t)| {
                 QueueItem {
-                    title: t.get_metadata().and_then(|metadata| metadata.title).unwrap_or_else(|| String::from("")),
+                    title: t.source.metadata.title.clone().unwrap_or_else(|| String::from("")),
                     index: i
                 }
             }).collect::<Vec<QueueItem>>()
@@ -233,8 +301,8 @@ impl MusicManager {
         MusicState {
             metadata,
             queue_names: self.get_items_in_queue(),
-            looping: self.looping,
-            shuffling: self.shuffling
+            looping: self.queue.looping(),
+            shuffling: self.queue.shuffling()
         }
     }
 }
\ No newline at end of file
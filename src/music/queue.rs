@@ -0,0 +1,292 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::music::state::QueueAction;
+
+/// Played tracks kept around so [QueueAction::Previous] has something to go back to
+pub const MAX_QUEUE_HISTORY: usize = 20;
+
+/// Track ordering, history and repeat rules, kept free of anything voice related so it can run on its own.
+/// Items before `next` have been played, `next` is the index of the track that plays after the current one.
+#[derive(Debug)]
+pub struct Queue<T> {
+    items: Vec<T>,
+    next: usize,
+    looping: bool,
+    shuffling: bool,
+    rng: StdRng
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Queue::with_rng(StdRng::from_entropy())
+    }
+}
+
+impl<T> Queue<T> {
+    /// Same seed and same calls give the same shuffle order
+    pub fn with_seed(seed: u64) -> Queue<T> {
+        Queue::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Queue<T> {
+        Queue {
+            items: vec![],
+            next: 0,
+            looping: false,
+            shuffling: false,
+            rng
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.neaten();
+        self.items.push(item);
+    }
+
+    /// Drops the oldest played track once the history is full
+    fn neaten(&mut self) {
+        if self.next > MAX_QUEUE_HISTORY {
+            self.items.remove(0);
+            self.next -= 1;
+        }
+    }
+
+    /// Moves `target` so it plays next, returns false if there is no such track
+    pub fn cut_line(&mut self, target: usize) -> bool {
+        if target >= self.items.len() { return false; }
+        let item = self.items.remove(target);
+        let next = if target < self.next { self.next - 1 } else { self.next };
+        self.items.insert(next, item);
+        self.next = next;
+        true
+    }
+
+    /// Turning looping on forgets the history so only the current and upcoming tracks repeat
+    pub fn toggle_loop(&mut self) {
+        self.looping = !self.looping;
+        if self.looping && self.next > 0 {
+            self.items = self.items.split_off((self.next - 1).min(self.items.len()));
+            // The current track is now first, the one after it still plays next
+            self.next = self.items.len().min(1);
+        }
+    }
+
+    pub fn toggle_shuffle(&mut self) {
+        self.shuffling = !self.shuffling;
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.next = 0;
+    }
+
+    /// Picks the track to play for `action` and moves past it, None once the queue has run out
+    pub fn advance(&mut self, action: QueueAction) -> Option<&mut T> {
+        let current = self.next.checked_sub(1);
+        if action == QueueAction::Previous {
+            self.next = self.next.saturating_sub(2);
+        }
+
+        if self.shuffling && action != QueueAction::SelectedNext && !self.items.is_empty() {
+            self.next = self.random_index(current);
+        }
+
+        if self.next >= self.items.len() && self.looping {
+            self.next = 0;
+        }
+
+        let index = self.next;
+        if index >= self.items.len() {
+            return None;
+        }
+        self.next += 1;
+        self.items.get_mut(index)
+    }
+
+    /// Any index but `current`, unless it is the only one
+    fn random_index(&mut self, current: Option<usize>) -> usize {
+        if self.items.len() == 1 { return 0; }
+        loop {
+            let index = self.rng.gen_range(0..self.items.len());
+            if Some(index) != current { return index; }
+        }
+    }
+
+    /// Tracks that can still be picked along with their index, in play order unless shuffling
+    pub fn upcoming(&self) -> impl Iterator<Item = (usize, &T)> {
+        let skip_amount = if self.shuffling { 0 } else { self.next };
+        self.items.iter().enumerate().skip(skip_amount)
+    }
+
+    pub fn looping(&self) -> bool {
+        self.looping
+    }
+
+    pub fn shuffling(&self) -> bool {
+        self.shuffling
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::music::state::QueueAction;
+    use super::{MAX_QUEUE_HISTORY, Queue};
+
+    const SEEDS: u64 = 200;
+    const STEPS: usize = 300;
+    const ACTIONS: [QueueAction; 4] = [QueueAction::HardNext, QueueAction::SoftNext, QueueAction::Previous, QueueAction::SelectedNext];
+
+    /// Items are numbered in push order so every one of them is distinct
+    fn filled(seed: u64, len: u32) -> Queue<u32> {
+        let mut queue = Queue::with_seed(seed);
+        for item in 0..len {
+            queue.push(item);
+        }
+        queue
+    }
+
+    fn current(queue: &Queue<u32>) -> Option<u32> {
+        queue.next.checked_sub(1).and_then(|index| queue.items.get(index)).copied()
+    }
+
+    /// Same items and position, the rng isn't carried over
+    fn snapshot(queue: &Queue<u32>) -> Queue<u32> {
+        Queue { items: queue.items.clone(), next: queue.next, looping: queue.looping, shuffling: queue.shuffling, rng: StdRng::seed_from_u64(0) }
+    }
+
+    /// Runs random operations against a queue, `check` sees the queue before and after each advance
+    fn random_session(seed: u64, mut check: impl FnMut(&Queue<u32>, QueueAction, Option<u32>, &Queue<u32>)) {
+        let mut ops = StdRng::seed_from_u64(seed);
+        let mut queue = Queue::with_seed(seed);
+        let mut pushed = 0;
+        for _ in 0..STEPS {
+            match ops.gen_range(0..100) {
+                0..=29 => {
+                    queue.push(pushed);
+                    pushed += 1;
+                }
+                30..=79 => {
+                    let action = ACTIONS[ops.gen_range(0..ACTIONS.len())];
+                    let before = snapshot(&queue);
+                    let played = queue.advance(action).copied();
+                    check(&before, action, played, &queue);
+                }
+                80..=89 => {
+                    let target = ops.gen_range(0..queue.len() + 1);
+                    queue.cut_line(target);
+                }
+                90..=94 => queue.toggle_shuffle(),
+                95..=98 => queue.toggle_loop(),
+                _ => queue.clear()
+            }
+            assert!(queue.next <= queue.items.len(), "seed {}: next {} past {} items", seed, queue.next, queue.items.len());
+        }
+    }
+
+    #[test]
+    fn advance_only_returns_items_in_the_queue() {
+        for seed in 0..SEEDS {
+            random_session(seed, |before, _, played, after| {
+                let played = match played {
+                    None => return,
+                    Some(played) => played
+                };
+                assert!(before.items.contains(&played), "seed {}: played {} which wasn't queued", seed, played);
+                assert_eq!(current(after), Some(played), "seed {}: next doesn't follow the played track", seed);
+            });
+        }
+    }
+
+    #[test]
+    fn shuffle_never_repeats_the_current_track() {
+        for seed in 0..SEEDS {
+            random_session(seed, |before, action, played, _| {
+                if !before.shuffling || action == QueueAction::SelectedNext || before.items.len() < 2 { return; }
+                if let Some(current) = current(before) {
+                    assert_ne!(played, Some(current), "seed {}: shuffle repeated {} out of {:?}", seed, current, before.items);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn neaten_keeps_next_on_the_same_item() {
+        let mut queue = filled(0, MAX_QUEUE_HISTORY as u32 + 10);
+        for _ in 0..MAX_QUEUE_HISTORY + 5 {
+            queue.advance(QueueAction::SoftNext);
+        }
+        let (current_before, next_before) = (current(&queue), queue.items.get(queue.next).copied());
+
+        queue.push(1000);
+        assert_eq!(queue.len(), MAX_QUEUE_HISTORY + 10, "the oldest track wasn't dropped");
+        assert_eq!(current(&queue), current_before);
+        assert_eq!(queue.items.get(queue.next).copied(), next_before);
+        assert_eq!(queue.advance(QueueAction::SoftNext).copied(), next_before);
+    }
+
+    #[test]
+    fn cut_line_then_selected_next_plays_the_selection() {
+        for seed in 0..SEEDS {
+            let mut ops = StdRng::seed_from_u64(seed);
+            let mut queue = filled(seed, ops.gen_range(1..30));
+            for _ in 0..ops.gen_range(0..queue.len()) {
+                queue.advance(QueueAction::SoftNext);
+            }
+            if ops.gen_bool(0.5) { queue.toggle_shuffle(); }
+            if ops.gen_bool(0.5) { queue.toggle_loop(); }
+
+            let target = ops.gen_range(0..queue.len());
+            let selected = queue.items[target];
+            assert!(queue.cut_line(target));
+            assert_eq!(queue.advance(QueueAction::SelectedNext).copied(), Some(selected), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn cut_line_refuses_missing_tracks() {
+        let mut queue = filled(0, 3);
+        assert!(!queue.cut_line(3));
+        assert_eq!(queue.items, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn looping_repeats_from_the_current_track() {
+        let mut queue = filled(0, 4);
+        queue.advance(QueueAction::SoftNext);
+        queue.advance(QueueAction::SoftNext);
+        queue.toggle_loop();
+
+        let played = (0..5).map(|_| queue.advance(QueueAction::SoftNext).copied()).collect::<Vec<Option<u32>>>();
+        assert_eq!(played, vec![Some(2), Some(3), Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn previous_goes_back_one_track() {
+        let mut queue = filled(0, 3);
+        queue.advance(QueueAction::SoftNext);
+        queue.advance(QueueAction::SoftNext);
+        assert_eq!(queue.advance(QueueAction::Previous).copied(), Some(0));
+        assert_eq!(queue.advance(QueueAction::SoftNext).copied(), Some(1));
+    }
+
+    #[test]
+    fn same_seed_shuffles_the_same_way() {
+        let order = |seed| {
+            let mut queue = filled(seed, 10);
+            queue.toggle_shuffle();
+            (0..20).map(|_| queue.advance(QueueAction::SoftNext).copied()).collect::<Vec<Option<u32>>>()
+        };
+        assert_eq!(order(7), order(7));
+    }
+}
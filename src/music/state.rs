@@ -1,6 +1,6 @@
 use songbird::input::Metadata;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct MusicState {
     pub metadata: Option<Box<Metadata>>,
     pub queue_names: Vec<QueueItem>,
@@ -14,7 +14,7 @@ pub struct QueueItem {
     pub index: usize
 }
 
-#[derive(PartialOrd, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialOrd, PartialEq, Eq)]
 pub enum QueueAction {
     HardNext,
     SoftNext,
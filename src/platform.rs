@@ -0,0 +1,274 @@
+#[cfg(test)]
+pub mod fake;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use serenity::async_trait;
+use serenity::builder::{CreateMessage, EditMessage};
+use serenity::client::Cache;
+use serenity::http::Http;
+use serenity::model::channel::Embed;
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+use songbird::{Call, Event, EventContext, EventHandler, Songbird};
+use songbird::input::{Input, Metadata, Restartable};
+use tokio::sync::Mutex;
+use tracing::error;
+
+use crate::error::AyakaResult;
+
+/// The message operations the music channel needs, [DiscordMessenger] in production and [fake::FakeMessenger] in tests
+#[async_trait]
+pub trait Messenger: Send + Sync {
+    async fn say(&self, channel_id: ChannelId, content: String) -> AyakaResult<MessageId>;
+
+    /// Fails if the user doesn't accept DMs from the bot
+    async fn dm(&self, user_id: UserId, content: String) -> AyakaResult<MessageId>;
+
+    async fn send(&self, channel_id: ChannelId, message: CreateMessage<'static>) -> AyakaResult<MessageId>;
+
+    /// Up to `limit` of the newest messages in a channel, newest first
+    async fn recent(&self, channel_id: ChannelId, limit: u64) -> AyakaResult<Vec<PostedMessage>>;
+
+    /// Whether the bot posted a message, fails if the message is gone
+    async fn is_own(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<bool>;
+
+    /// First embed of a message, used to carry over fields a partial menu update doesn't touch
+    async fn embed(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<Option<Embed>>;
+
+    async fn edit(&self, channel_id: ChannelId, message_id: MessageId, edit: EditMessage<'static>) -> AyakaResult<()>;
+
+    async fn delete(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PostedMessage {
+    pub id: MessageId,
+    /// Posted by the bot itself
+    pub own: bool
+}
+
+/// Turns a link or search into something playable, [YtdlSources] in production and [fake::FakeSources] in tests
+#[async_trait]
+pub trait SourceResolver: Send + Sync {
+    async fn resolve(&self, url: String) -> AyakaResult<Source>;
+
+    async fn search(&self, query: String) -> AyakaResult<Source>;
+}
+
+/// A resolved track, the queue holds these until they play
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub metadata: Metadata,
+    kind: SourceKind
+}
+
+#[derive(Clone, Debug)]
+enum SourceKind {
+    Ytdl(Restartable),
+    /// Only carries metadata, see [fake::FakeCall]
+    #[cfg(test)]
+    Fake
+}
+
+impl Source {
+    pub fn ytdl(source: Restartable) -> Source {
+        Source { metadata: source.get_metadata().unwrap_or_default(), kind: SourceKind::Ytdl(source) }
+    }
+
+    #[cfg(test)]
+    pub fn fake(metadata: Metadata) -> Source {
+        Source { metadata, kind: SourceKind::Fake }
+    }
+
+    /// None for sources that can't actually be played
+    fn into_input(self) -> Option<Input> {
+        match self.kind {
+            SourceKind::Ytdl(source) => Some(source.into()),
+            #[cfg(test)]
+            SourceKind::Fake => None
+        }
+    }
+}
+
+/// Joins and leaves voice channels, [SongbirdVoice] in production and [fake::FakeVoice] in tests
+#[async_trait]
+pub trait VoiceConnector: Send + Sync {
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> AyakaResult<Arc<dyn VoiceCall>>;
+
+    async fn leave(&self, guild_id: GuildId) -> AyakaResult<()>;
+
+    /// Members who can hear a voice channel, deafened members and bots are left out
+    fn listeners(&self, guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId>;
+}
+
+/// A guild's voice connection
+#[async_trait]
+pub trait VoiceCall: Send + Sync + Debug {
+    /// Identifies the underlying connection, handles to the same call return the same id
+    fn call_id(&self) -> usize;
+
+    async fn current_channel(&self) -> Option<ChannelId>;
+
+    /// Stops whatever is playing and starts `source`, `volume` is a multiplier where 1.0 is unchanged
+    async fn play_only(&self, source: Source, volume: f32);
+
+    async fn stop(&self);
+
+    async fn add_global_event(&self, event: Event, handler: Box<dyn EventHandler>);
+}
+
+/// The cache is only used to tell the bot's own messages apart
+pub struct DiscordMessenger {
+    cache: Arc<Cache>,
+    http: Arc<Http>
+}
+
+impl DiscordMessenger {
+    pub fn new(cache: Arc<Cache>, http: Arc<Http>) -> DiscordMessenger {
+        DiscordMessenger { cache, http }
+    }
+}
+
+#[async_trait]
+impl Messenger for DiscordMessenger {
+    async fn say(&self, channel_id: ChannelId, content: String) -> AyakaResult<MessageId> {
+        Ok(channel_id.say(&self.http, content).await?.id)
+    }
+
+    async fn dm(&self, user_id: UserId, content: String) -> AyakaResult<MessageId> {
+        let channel = user_id.create_dm_channel(&self.http).await?;
+        Ok(channel.say(&self.http, content).await?.id)
+    }
+
+    async fn send(&self, channel_id: ChannelId, message: CreateMessage<'static>) -> AyakaResult<MessageId> {
+        let message = channel_id.send_message(&self.http, |builder| {
+            *builder = message;
+            builder
+        }).await?;
+        Ok(message.id)
+    }
+
+    async fn recent(&self, channel_id: ChannelId, limit: u64) -> AyakaResult<Vec<PostedMessage>> {
+        let messages = channel_id.messages(&self.http, |builder| builder.limit(limit)).await?;
+        Ok(messages.iter().map(|message| PostedMessage { id: message.id, own: message.is_own(&self.cache) }).collect())
+    }
+
+    async fn is_own(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<bool> {
+        Ok(channel_id.message(&self.http, message_id).await?.is_own(&self.cache))
+    }
+
+    async fn embed(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<Option<Embed>> {
+        Ok(channel_id.message(&self.http, message_id).await?.embeds.into_iter().next())
+    }
+
+    async fn edit(&self, channel_id: ChannelId, message_id: MessageId, edit: EditMessage<'static>) -> AyakaResult<()> {
+        channel_id.edit_message(&self.http, message_id, |builder| {
+            *builder = edit;
+            builder
+        }).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, channel_id: ChannelId, message_id: MessageId) -> AyakaResult<()> {
+        Ok(channel_id.delete_message(&self.http, message_id).await?)
+    }
+}
+
+pub struct YtdlSources;
+
+#[async_trait]
+impl SourceResolver for YtdlSources {
+    async fn resolve(&self, url: String) -> AyakaResult<Source> {
+        Ok(Source::ytdl(Restartable::ytdl(url, true).await?))
+    }
+
+    async fn search(&self, query: String) -> AyakaResult<Source> {
+        Ok(Source::ytdl(Restartable::ytdl_search(query, true).await?))
+    }
+}
+
+/// Voice states come from the cache, songbird only knows about the bot's own connection
+pub struct SongbirdVoice {
+    songbird: Arc<Songbird>,
+    cache: Arc<Cache>
+}
+
+impl SongbirdVoice {
+    pub fn new(songbird: Arc<Songbird>, cache: Arc<Cache>) -> SongbirdVoice {
+        SongbirdVoice { songbird, cache }
+    }
+}
+
+#[async_trait]
+impl VoiceConnector for SongbirdVoice {
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> AyakaResult<Arc<dyn VoiceCall>> {
+        let (call, result) = self.songbird.join(guild_id, channel_id).await;
+        result?;
+        Ok(Arc::new(SongbirdCall { call }))
+    }
+
+    async fn leave(&self, guild_id: GuildId) -> AyakaResult<()> {
+        Ok(self.songbird.remove(guild_id).await?)
+    }
+
+    fn listeners(&self, guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId> {
+        let guild = match self.cache.guild(guild_id) {
+            None => return vec![],
+            Some(guild) => guild
+        };
+        let own_id = self.cache.current_user_id();
+        guild.voice_states.values()
+            .filter(|voice| voice.channel_id == Some(channel_id) && !voice.deaf && !voice.self_deaf)
+            // The bot isn't always in the user cache, so it is left out by id rather than relying on the bot flag
+            .filter(|voice| voice.user_id != own_id)
+            .filter(|voice| !self.cache.user(voice.user_id).is_some_and(|user| user.bot))
+            .map(|voice| voice.user_id)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct SongbirdCall {
+    call: Arc<Mutex<Call>>
+}
+
+#[async_trait]
+impl VoiceCall for SongbirdCall {
+    fn call_id(&self) -> usize {
+        Arc::as_ptr(&self.call) as usize
+    }
+
+    async fn current_channel(&self) -> Option<ChannelId> {
+        self.call.lock().await.current_channel().map(|channel| ChannelId(channel.0))
+    }
+
+    async fn play_only(&self, source: Source, volume: f32) {
+        let input = match source.into_input() {
+            None => return,
+            Some(input) => input
+        };
+        let track = self.call.lock().await.play_only_source(input);
+        if let Err(err) = track.set_volume(volume) {
+            error!("Unable to set volume: {}", err);
+        }
+    }
+
+    async fn stop(&self) {
+        self.call.lock().await.stop();
+    }
+
+    async fn add_global_event(&self, event: Event, handler: Box<dyn EventHandler>) {
+        self.call.lock().await.add_global_event(event, BoxedHandler(handler));
+    }
+}
+
+/// Songbird wants a concrete handler type, this forwards to one chosen at runtime
+struct BoxedHandler(Box<dyn EventHandler>);
+
+#[async_trait]
+impl EventHandler for BoxedHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        self.0.act(ctx).await
+    }
+}
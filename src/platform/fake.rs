@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use serde_json::Value;
+use serenity::async_trait;
+use serenity::builder::{CreateMessage, EditMessage};
+use serenity::model::channel::Embed;
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+use songbird::{Event, EventHandler};
+use songbird::input::Metadata;
+
+use crate::error::{AyakaError, AyakaResult};
+use crate::platform::{Messenger, PostedMessage, Source, SourceResolver, VoiceCall, VoiceConnector};
+
+/// Keeps every message in memory, edits are stored as the json serenity would have sent
+#[derive(Default)]
+pub struct FakeMessenger {
+    next_id: AtomicU64,
+    pub messages: Mutex<HashMap<MessageId, FakeMessage>>,
+    /// Every DM in the order it was sent
+    pub dms: Mutex<Vec<(UserId, String)>>
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeMessage {
+    pub channel_id: ChannelId,
+    pub content: String,
+    /// Posted by the bot rather than a member
+    pub own: bool,
+    /// The message as sent followed by every edit
+    pub edits: Vec<HashMap<&'static str, Value>>
+}
+
+impl FakeMessenger {
+    /// Adds a message as if the bot had posted it before the test started, e.g. the music embed
+    pub fn insert(&self, channel_id: ChannelId) -> MessageId {
+        self.insert_message(channel_id, true)
+    }
+
+    /// Adds a message a member posted
+    pub fn insert_foreign(&self, channel_id: ChannelId) -> MessageId {
+        self.insert_message(channel_id, false)
+    }
+
+    fn insert_message(&self, channel_id: ChannelId, own: bool) -> MessageId {
+        let id = MessageId(self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        self.messages.lock().insert(id, FakeMessage { channel_id, content: String::new(), own, edits: vec![] });
+        id
+    }
+
+    pub fn in_channel(&self, channel_id: ChannelId) -> Vec<FakeMessage> {
+        self.messages.lock().values().filter(|message| message.channel_id == channel_id).cloned().collect()
+    }
+}
+
+#[async_trait]
+impl Messenger for FakeMessenger {
+    async fn say(&self, channel_id: ChannelId, content: String) -> AyakaResult<MessageId> {
+        let id = self.insert(channel_id);
+        if let Some(message) = self.messages.lock().get_mut(&id) {
+            message.content = content;
+        }
+        Ok(id)
+    }
+
+    async fn dm(&self, user_id: UserId, content: String) -> AyakaResult<MessageId> {
+        self.dms.lock().push((user_id, content));
+        Ok(MessageId(self.next_id.fetch_add(1, Ordering::Relaxed) + 1))
+    }
+
+    async fn send(&self, channel_id: ChannelId, message: CreateMessage<'static>) -> AyakaResult<MessageId> {
+        let id = self.insert(channel_id);
+        if let Some(posted) = self.messages.lock().get_mut(&id) {
+            posted.edits.push(message.0);
+        }
+        Ok(id)
+    }
+
+    async fn recent(&self, channel_id: ChannelId, limit: u64) -> AyakaResult<Vec<PostedMessage>> {
+        let mut messages = self.messages.lock().iter()
+            .filter(|(_, message)| message.channel_id == channel_id)
+            .map(|(id, message)| PostedMessage { id: *id, own: message.own })
+            .collect::<Vec<PostedMessage>>();
+        // Ids are handed out in order, so the highest is the newest
+        messages.sort_by(|a, b| b.id.cmp(&a.id));
+        messages.truncate(limit as usize);
+        Ok(messages)
+    }
+
+    async fn is_own(&self, _channel_id: ChannelId, message_id: MessageId) -> AyakaResult<bool> {
+        let messages = self.messages.lock();
+        let message = messages.get(&message_id).ok_or(AyakaError::Corrupt(format!("No message {}", message_id)))?;
+        Ok(message.own)
+    }
+
+    async fn embed(&self, _channel_id: ChannelId, message_id: MessageId) -> AyakaResult<Option<Embed>> {
+        let messages = self.messages.lock();
+        let message = messages.get(&message_id).ok_or(AyakaError::Corrupt(format!("No message {}", message_id)))?;
+        let embed = message.edits.last()
+            .and_then(|edit| edit.get("embeds"))
+            .and_then(|embeds| embeds.get(0))
+            .and_then(|embed| serde_json::from_value(embed.clone()).ok());
+        Ok(embed)
+    }
+
+    async fn edit(&self, _channel_id: ChannelId, message_id: MessageId, edit: EditMessage<'static>) -> AyakaResult<()> {
+        let mut messages = self.messages.lock();
+        let message = messages.get_mut(&message_id).ok_or(AyakaError::Corrupt(format!("No message {}", message_id)))?;
+        message.edits.push(edit.0);
+        Ok(())
+    }
+
+    async fn delete(&self, _channel_id: ChannelId, message_id: MessageId) -> AyakaResult<()> {
+        self.messages.lock().remove(&message_id);
+        Ok(())
+    }
+}
+
+/// Resolves anything instantly, searches get a made up link so they can be told apart from links
+#[derive(Default)]
+pub struct FakeSources;
+
+#[async_trait]
+impl SourceResolver for FakeSources {
+    async fn resolve(&self, url: String) -> AyakaResult<Source> {
+        Ok(Source::fake(Metadata { title: Some(url.clone()), source_url: Some(url), ..Default::default() }))
+    }
+
+    async fn search(&self, query: String) -> AyakaResult<Source> {
+        let url = format!("https://fake.invalid/{}", query.replace(' ', "-"));
+        Ok(Source::fake(Metadata { title: Some(query), source_url: Some(url), ..Default::default() }))
+    }
+}
+
+/// Hands out one [FakeCall] per guild, the same way songbird reuses a guild's call
+#[derive(Default)]
+pub struct FakeVoice {
+    pub calls: Mutex<HashMap<GuildId, Arc<FakeCall>>>,
+    /// Who [VoiceConnector::listeners] reports for each voice channel
+    pub listeners: Mutex<HashMap<ChannelId, Vec<UserId>>>
+}
+
+#[async_trait]
+impl VoiceConnector for FakeVoice {
+    async fn join(&self, guild_id: GuildId, channel_id: ChannelId) -> AyakaResult<Arc<dyn VoiceCall>> {
+        let call = self.calls.lock().entry(guild_id).or_default().clone();
+        *call.channel.lock() = Some(channel_id);
+        Ok(call)
+    }
+
+    async fn leave(&self, guild_id: GuildId) -> AyakaResult<()> {
+        if let Some(call) = self.calls.lock().remove(&guild_id) {
+            *call.channel.lock() = None;
+        }
+        Ok(())
+    }
+
+    fn listeners(&self, _guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId> {
+        self.listeners.lock().get(&channel_id).cloned().unwrap_or_default()
+    }
+}
+
+/// Records what would have been played instead of running a driver
+#[derive(Default)]
+pub struct FakeCall {
+    pub channel: Mutex<Option<ChannelId>>,
+    /// Title of every source passed to [VoiceCall::play_only], in order
+    pub played: Mutex<Vec<Option<String>>>,
+    /// Volume each of `played` started at
+    pub volumes: Mutex<Vec<f32>>,
+    pub stops: AtomicU64,
+    pub events: Mutex<Vec<(Event, Box<dyn EventHandler>)>>
+}
+
+impl std::fmt::Debug for FakeCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FakeCall")
+            .field("channel", &self.channel)
+            .field("played", &self.played)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl VoiceCall for FakeCall {
+    fn call_id(&self) -> usize {
+        self as *const FakeCall as usize
+    }
+
+    async fn current_channel(&self) -> Option<ChannelId> {
+        *self.channel.lock()
+    }
+
+    async fn play_only(&self, source: Source, volume: f32) {
+        self.played.lock().push(source.metadata.title.clone());
+        self.volumes.lock().push(volume);
+    }
+
+    async fn stop(&self) {
+        self.stops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn add_global_event(&self, event: Event, handler: Box<dyn EventHandler>) {
+        self.events.lock().push((event, handler));
+    }
+}
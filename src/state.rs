@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serenity::client::{Cache, Context};
+use serenity::http::{CacheHttp, Http};
+use parking_lot::{Mutex, RwLock};
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::prelude::TypeMapKey;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::guild::GuildManager;
+use crate::guild::actor::GuildHandle;
+use crate::platform::{DiscordMessenger, Messenger, SourceResolver, VoiceConnector, YtdlSources};
+use crate::storage::{DirtyGuilds, GuildStore};
+
+/// Everything the bot shares between guilds, one per client so several can run in a process
+pub struct AppState {
+    pub config: Arc<Config>,
+    /// Only held long enough to clone a handle, all guild work goes through its actor
+    pub guilds: RwLock<HashMap<GuildId, GuildHandle>>,
+    pub cache: Arc<Cache>,
+    pub http: Arc<Http>,
+    pub store: Arc<dyn GuildStore>,
+    pub dirty: Arc<DirtyGuilds>,
+    /// Music channel messages go through this instead of [Http] so they can be faked
+    pub messenger: Arc<dyn Messenger>,
+    pub voice: Arc<dyn VoiceConnector>,
+    pub sources: Arc<dyn SourceResolver>,
+    /// Periodic jobs such as saving, replaced on every ready and stopped on shutdown
+    pub scheduler: Mutex<Vec<JoinHandle<()>>>,
+    /// Copy of every [GuildManager::prefix] that is set, read for each message without waiting on the guild's actor
+    prefixes: RwLock<HashMap<GuildId, String>>,
+    /// Control channel of every player, so messages outside music channels never wait on a guild's actor
+    music_channels: RwLock<HashMap<ChannelId, GuildId>>,
+    shutting_down: AtomicBool,
+    /// Set by the first ready, the guilds on disk are only loaded once per process
+    loaded: AtomicBool
+}
+
+pub struct AppStateKey;
+
+impl TypeMapKey for AppStateKey {
+    type Value = Arc<AppState>;
+}
+
+impl AppState {
+    pub fn new(config: Arc<Config>, cache: Arc<Cache>, http: Arc<Http>, store: Arc<dyn GuildStore>, voice: Arc<dyn VoiceConnector>) -> AppState {
+        let messenger = Arc::new(DiscordMessenger::new(cache.clone(), http.clone()));
+        AppState::build(config, cache, http, store, messenger, voice, Arc::new(YtdlSources))
+    }
+
+    /// State that never reaches discord, the cache stays empty and the http client has no token.
+    /// Only what goes through the platform traits works, see [crate::platform::fake].
+    #[cfg(test)]
+    pub fn offline(config: Config, store: Arc<dyn GuildStore>, messenger: Arc<dyn Messenger>, voice: Arc<dyn VoiceConnector>, sources: Arc<dyn SourceResolver>) -> AppState {
+        AppState::build(Arc::new(config), Arc::new(Cache::default()), Arc::new(Http::new("")), store, messenger, voice, sources)
+    }
+
+    fn build(config: Arc<Config>, cache: Arc<Cache>, http: Arc<Http>, store: Arc<dyn GuildStore>, messenger: Arc<dyn Messenger>,
+             voice: Arc<dyn VoiceConnector>, sources: Arc<dyn SourceResolver>) -> AppState {
+        AppState {
+            config,
+            guilds: RwLock::new(HashMap::new()),
+            cache,
+            http,
+            store,
+            dirty: Arc::new(DirtyGuilds::default()),
+            messenger,
+            voice,
+            sources,
+            scheduler: Mutex::new(vec![]),
+            prefixes: RwLock::new(HashMap::new()),
+            music_channels: RwLock::new(HashMap::new()),
+            shutting_down: AtomicBool::new(false),
+            loaded: AtomicBool::new(false)
+        }
+    }
+
+    /// Pulls the state out of the client's data, it is inserted before the client starts
+    pub async fn from_context(ctx: &Context) -> Arc<AppState> {
+        ctx.data.read().await.get::<AppStateKey>().expect("AppState not inserted into client data").clone()
+    }
+
+    pub fn guild(&self, guild_id: GuildId) -> Option<GuildHandle> {
+        self.guilds.read().get(&guild_id).cloned()
+    }
+
+    /// Gets the guild's actor or spawns one with an empty manager
+    pub fn guild_or_default(&self, guild_id: GuildId) -> GuildHandle {
+        self.guilds.write().entry(guild_id).or_insert_with(|| {
+            let manager = GuildManager::new(guild_id, self.dirty.clone());
+            manager.mark_dirty();
+            GuildHandle::spawn(manager)
+        }).clone()
+    }
+
+    pub fn register_guild(&self, manager: GuildManager) -> GuildHandle {
+        let handle = GuildHandle::spawn(manager);
+        self.guilds.write().insert(handle.id, handle.clone());
+        handle
+    }
+
+    /// Unregisters a guild, its actor stops once the returned handle and any clones of it are dropped
+    pub fn remove_guild(&self, guild_id: GuildId) -> Option<GuildHandle> {
+        self.guilds.write().remove(&guild_id)
+    }
+
+    /// Set once shutdown starts so voice events stop reconnecting
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        for job in self.scheduler.lock().drain(..) {
+            job.abort();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// True only for the first call, later ready events must keep the guilds that are already running
+    pub fn begin_loading(&self) -> bool {
+        !self.loaded.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn all_guilds(&self) -> Vec<GuildHandle> {
+        self.guilds.read().values().cloned().collect()
+    }
+
+    /// Text command prefix of a guild, the configured one unless the guild changed it
+    pub fn prefix(&self, guild_id: GuildId) -> String {
+        match self.prefixes.read().get(&guild_id) {
+            Some(prefix) => prefix.clone(),
+            None => self.config.prefix.clone()
+        }
+    }
+
+    /// Only meant to be called by [GuildManager::set_prefix] and when a guild is loaded or forgotten
+    pub fn cache_prefix(&self, guild_id: GuildId, prefix: Option<String>) {
+        match prefix {
+            None => self.prefixes.write().remove(&guild_id),
+            Some(prefix) => self.prefixes.write().insert(guild_id, prefix)
+        };
+    }
+
+    pub fn is_music_channel(&self, channel_id: ChannelId) -> bool {
+        self.music_channels.read().contains_key(&channel_id)
+    }
+
+    /// Replaces the music channels of a guild, only meant to be called by [GuildManager] when its players change
+    /// and when a guild is forgotten
+    pub fn cache_music_channels(&self, guild_id: GuildId, channels: impl Iterator<Item = ChannelId>) {
+        let mut music_channels = self.music_channels.write();
+        music_channels.retain(|_, owner| *owner != guild_id);
+        music_channels.extend(channels.map(|channel_id| (channel_id, guild_id)));
+    }
+}
+
+impl CacheHttp for AppState {
+    fn http(&self) -> &Http {
+        &self.http
+    }
+
+    fn cache(&self) -> Option<&Arc<Cache>> {
+        Some(&self.cache)
+    }
+}
+
+impl AsRef<Http> for AppState {
+    fn as_ref(&self) -> &Http {
+        &self.http
+    }
+}
+
+impl AsRef<Cache> for AppState {
+    fn as_ref(&self) -> &Cache {
+        &self.cache
+    }
+}
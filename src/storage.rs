@@ -0,0 +1,203 @@
+pub mod sqlite;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serenity::client::Context;
+use serenity::model::id::GuildId;
+use tracing::error;
+use tracing::log::{Level, log};
+
+use crate::config::StorageConfig;
+use crate::error::AyakaResult;
+use crate::guild::GuildManager;
+use crate::json::{GuildJson, JsonGuildStore};
+use crate::state::AppState;
+use crate::storage::sqlite::SqliteGuildStore;
+
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(3);
+/// A guild that keeps changing, like one with a busy queue, is still saved this often
+const SAVE_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Persistence backend for everything the bot remembers about a guild
+pub trait GuildStore: Send + Sync {
+    fn load_guilds(&self) -> AyakaResult<Vec<GuildJson>>;
+
+    fn save_guild(&self, guild: GuildJson) -> AyakaResult<()>;
+
+    fn delete_guild(&self, guild_id: GuildId) -> AyakaResult<()>;
+
+    /// Persists any writes the store has buffered
+    fn flush(&self) -> AyakaResult<()>;
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Json,
+    Sqlite
+}
+
+/// Guilds with unsaved changes, shared by every [GuildManager] of an [AppState]
+#[derive(Default, Debug)]
+pub struct DirtyGuilds {
+    inner: parking_lot::Mutex<HashMap<GuildId, DirtySince>>
+}
+
+#[derive(Copy, Clone, Debug)]
+struct DirtySince {
+    first_change: Instant,
+    last_change: Instant
+}
+
+pub fn open_store(storage: &StorageConfig) -> Arc<dyn GuildStore> {
+    match storage.backend {
+        StorageBackend::Json => Arc::new(JsonGuildStore::new(&storage.json_path, &storage.backup_dir)),
+        StorageBackend::Sqlite => {
+            let store = SqliteGuildStore::open(&storage.sqlite_path)
+                .unwrap_or_else(|err| panic!("Unable to open {}: {}", storage.sqlite_path.display(), err));
+            if let Err(err) = migrate_json_to(storage, &store) {
+                error!("Unable to migrate {} into {}: {}", storage.json_path.display(), storage.sqlite_path.display(), err);
+            }
+            Arc::new(store)
+        }
+    }
+}
+
+/// One-shot import of an existing json cache, the json file is renamed afterwards so it only runs once
+fn migrate_json_to(storage: &StorageConfig, store: &dyn GuildStore) -> AyakaResult<()> {
+    if !storage.json_path.exists() {
+        return Ok(());
+    }
+
+    let guilds = JsonGuildStore::new(&storage.json_path, &storage.backup_dir).load_guilds()?;
+    let count = guilds.len();
+    for guild in guilds {
+        store.save_guild(guild)?;
+    }
+    store.flush()?;
+
+    let migrated = storage.json_path.with_extension("json.migrated");
+    fs::rename(&storage.json_path, &migrated)?;
+    log!(Level::Info, "Migrated {} guilds from {}, old file kept as {}", count, storage.json_path.display(), migrated.display());
+    Ok(())
+}
+
+pub async fn load_guilds_to_cache(ctx: &Context, state: &Arc<AppState>) -> AyakaResult<()> {
+    let guilds = state.store.load_guilds()?;
+
+    for guild_json in guilds {
+        let guild_manager = GuildManager::from_json(ctx, state, guild_json).await;
+        state.register_guild(guild_manager);
+    }
+    Ok(())
+}
+
+impl DirtyGuilds {
+    /// Flags a guild's persisted state as changed so the next debounced save picks it up
+    pub fn mark(&self, guild_id: GuildId) {
+        let now = Instant::now();
+        self.inner.lock().entry(guild_id)
+            .and_modify(|since| since.last_change = now)
+            .or_insert(DirtySince { first_change: now, last_change: now });
+    }
+
+    /// Takes the guilds whose changes settled for [SAVE_DEBOUNCE], or that have waited [SAVE_MAX_DELAY] since their first unsaved change
+    fn take_settled(&self, now: Instant) -> Vec<GuildId> {
+        let mut dirty = self.inner.lock();
+        let settled = dirty.iter()
+            .filter(|(_, since)| now.saturating_duration_since(since.last_change) >= SAVE_DEBOUNCE || now.saturating_duration_since(since.first_change) >= SAVE_MAX_DELAY)
+            .map(|(guild_id, _)| *guild_id)
+            .collect::<Vec<GuildId>>();
+        for guild_id in &settled {
+            dirty.remove(guild_id);
+        }
+        settled
+    }
+
+    fn clear(&self) {
+        self.inner.lock().clear();
+    }
+}
+
+async fn snapshot(state: &AppState, guild_id: GuildId) -> Option<GuildJson> {
+    state.guild(guild_id)?.call(|guild| Box::pin(async move { guild.to_json_struct() })).await
+}
+
+/// Called by the scheduler, only locks and writes the guilds that were marked dirty
+pub async fn save_if_dirty(state: &AppState) {
+    let dirty_ids = state.dirty.take_settled(Instant::now());
+    if dirty_ids.is_empty() {
+        return;
+    }
+
+    let mut result = Ok(());
+    for guild_id in &dirty_ids {
+        let saved = match snapshot(state, *guild_id).await {
+            Some(guild_json) => state.store.save_guild(guild_json),
+            None => state.store.delete_guild(*guild_id)
+        };
+        result = result.and(saved);
+    }
+
+    if let Err(err) = result.and_then(|_| state.store.flush()) {
+        error!("Error saving guilds: {}", err);
+        for guild_id in dirty_ids {
+            state.dirty.mark(guild_id);
+        }
+    }
+}
+
+/// Unconditionally saves every guild, used for the final flush on shutdown
+pub async fn save_guilds_to_disk(state: &AppState) {
+    state.dirty.clear();
+    for handle in state.all_guilds() {
+        let guild_json = match handle.call(|guild| Box::pin(async move { guild.to_json_struct() })).await {
+            None => continue,
+            Some(guild_json) => guild_json
+        };
+        if let Err(err) = state.store.save_guild(guild_json) {
+            error!("Error saving guild: {}", err);
+        }
+    }
+    if let Err(err) = state.store.flush() {
+        error!("Error saving guilds: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serenity::model::id::GuildId;
+
+    use crate::storage::{DirtyGuilds, SAVE_DEBOUNCE, SAVE_MAX_DELAY};
+
+    #[test]
+    fn a_busy_guild_does_not_hold_back_a_settled_one() {
+        let dirty = DirtyGuilds::default();
+        let (quiet, busy) = (GuildId(1), GuildId(2));
+        dirty.mark(quiet);
+        dirty.mark(busy);
+        dirty.inner.lock().get_mut(&busy).unwrap().last_change += SAVE_DEBOUNCE;
+
+        let now = Instant::now() + SAVE_DEBOUNCE;
+        assert_eq!(dirty.take_settled(now), vec![quiet]);
+        assert!(dirty.inner.lock().contains_key(&busy));
+    }
+
+    #[test]
+    fn a_guild_that_never_settles_is_saved_after_the_max_delay() {
+        let dirty = DirtyGuilds::default();
+        let guild_id = GuildId(1);
+        dirty.mark(guild_id);
+        let start = Instant::now();
+        dirty.inner.lock().get_mut(&guild_id).unwrap().last_change = start + SAVE_MAX_DELAY;
+
+        assert!(dirty.take_settled(start + SAVE_MAX_DELAY - Duration::from_secs(1)).is_empty());
+        assert_eq!(dirty.take_settled(start + SAVE_MAX_DELAY + Duration::from_secs(1)), vec![guild_id]);
+    }
+}
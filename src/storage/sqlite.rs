@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use serenity::model::id::GuildId;
+
+use crate::error::AyakaResult;
+use crate::json::{GuildJson, HistoryJson, MemberJson, PlayerJson};
+use crate::music::history::MAX_PLAY_HISTORY;
+use crate::storage::GuildStore;
+
+/// Applied in order, `PRAGMA user_version` records how many have already run
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE guilds (
+        guild_id INTEGER PRIMARY KEY,
+        music_channel INTEGER,
+        channel_setup INTEGER NOT NULL,
+        stay_connected INTEGER,
+        idle_source TEXT
+    );
+    CREATE TABLE play_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        guild_id INTEGER NOT NULL REFERENCES guilds(guild_id) ON DELETE CASCADE,
+        number INTEGER NOT NULL,
+        played_at INTEGER NOT NULL,
+        requester_id INTEGER,
+        title TEXT,
+        url TEXT,
+        duration_secs INTEGER
+    );
+    CREATE INDEX play_history_guild ON play_history (guild_id, played_at);
+    CREATE TABLE members (
+        guild_id INTEGER NOT NULL REFERENCES guilds(guild_id) ON DELETE CASCADE,
+        user_id INTEGER NOT NULL,
+        tracks_requested INTEGER NOT NULL,
+        listening_secs INTEGER NOT NULL,
+        volume INTEGER,
+        dm_now_playing INTEGER NOT NULL,
+        PRIMARY KEY (guild_id, user_id)
+    );
+    CREATE TABLE member_tracks (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        PRIMARY KEY (guild_id, user_id, title),
+        FOREIGN KEY (guild_id, user_id) REFERENCES members(guild_id, user_id) ON DELETE CASCADE
+    );
+    CREATE TABLE member_artists (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        artist TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        PRIMARY KEY (guild_id, user_id, artist),
+        FOREIGN KEY (guild_id, user_id) REFERENCES members(guild_id, user_id) ON DELETE CASCADE
+    );
+    CREATE TABLE member_blocked (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        PRIMARY KEY (guild_id, user_id, url),
+        FOREIGN KEY (guild_id, user_id) REFERENCES members(guild_id, user_id) ON DELETE CASCADE
+    );",
+    "ALTER TABLE guilds ADD COLUMN music_message INTEGER;",
+    "CREATE TABLE guild_players (
+        channel_id INTEGER PRIMARY KEY,
+        guild_id INTEGER NOT NULL REFERENCES guilds(guild_id) ON DELETE CASCADE,
+        voice_channel INTEGER NOT NULL,
+        message_id INTEGER
+    );",
+    "ALTER TABLE guilds ADD COLUMN prefix TEXT;",
+];
+
+/// Embedded database backend, each guild setting is its own column so it can be queried directly
+pub struct SqliteGuildStore {
+    connection: Mutex<Connection>,
+    /// Locked after `connection`
+    saved: Mutex<HashMap<GuildId, SavedRows>>
+}
+
+/// What the database already holds for a guild, so saving only writes the rows that changed
+#[derive(Default)]
+struct SavedRows {
+    /// Each member as stored, by user id
+    members: HashMap<u64, MemberJson>,
+    /// Newest play in `play_history`
+    last_play: Option<HistoryJson>
+}
+
+impl SqliteGuildStore {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<SqliteGuildStore> {
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        migrate(&mut connection)?;
+        Ok(SqliteGuildStore { connection: Mutex::new(connection), saved: Mutex::new(HashMap::new()) })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+impl GuildStore for SqliteGuildStore {
+    fn load_guilds(&self) -> AyakaResult<Vec<GuildJson>> {
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare(
+            "SELECT guild_id, music_channel, channel_setup, stay_connected, idle_source, music_message, prefix FROM guilds"
+        )?;
+
+        let guilds = statement.query_map([], |row| Ok(GuildJson {
+            guild_id: row.get::<_, i64>(0)? as u64,
+            music_channel: row.get::<_, Option<i64>>(1)?.map(|id| id as u64),
+            channel_setup: row.get(2)?,
+            stay_connected: row.get::<_, Option<i64>>(3)?.map(|id| id as u64),
+            idle_source: row.get(4)?,
+            music_message: row.get::<_, Option<i64>>(5)?.map(|id| id as u64),
+            players: vec![],
+            prefix: row.get(6)?,
+            members: vec![],
+            history: vec![]
+        }))?;
+        let mut guilds = guilds.collect::<rusqlite::Result<Vec<GuildJson>>>()?;
+
+        let mut statement = connection.prepare(
+            "SELECT guild_id, channel_id, voice_channel, message_id FROM guild_players ORDER BY rowid"
+        )?;
+        let players = statement.query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, PlayerJson {
+            channel: row.get::<_, i64>(1)? as u64,
+            voice_channel: row.get::<_, i64>(2)? as u64,
+            message: row.get::<_, Option<i64>>(3)?.map(|id| id as u64)
+        })))?;
+        for player in players {
+            let (guild_id, player) = player?;
+            if let Some(guild) = guilds.iter_mut().find(|guild| guild.guild_id == guild_id) {
+                guild.players.push(player);
+            }
+        }
+
+        let mut members = load_members(&connection)?;
+        let mut saved = self.saved.lock();
+        for guild in &mut guilds {
+            let guild_members = members.remove(&guild.guild_id).unwrap_or_default();
+            saved.entry(GuildId(guild.guild_id)).or_default().members = guild_members.iter()
+                .map(|member| (member.user_id, member.clone()))
+                .collect();
+            guild.members = guild_members;
+        }
+
+        let mut statement = connection.prepare(
+            "SELECT guild_id, number, played_at, requester_id, title, url, duration_secs FROM play_history ORDER BY id"
+        )?;
+        let plays = statement.query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, HistoryJson {
+            number: row.get::<_, i64>(1)? as u64,
+            played_at: row.get(2)?,
+            requester: row.get::<_, Option<i64>>(3)?.map(|id| id as u64),
+            title: row.get(4)?,
+            url: row.get(5)?,
+            duration_secs: row.get::<_, Option<i64>>(6)?.map(|secs| secs as u64)
+        })))?;
+        for play in plays {
+            let (guild_id, play) = play?;
+            if let Some(guild) = guilds.iter_mut().find(|guild| guild.guild_id == guild_id) {
+                guild.history.push(play);
+            }
+        }
+        for guild in &guilds {
+            saved.entry(GuildId(guild.guild_id)).or_default().last_play = guild.history.last().cloned();
+        }
+        Ok(guilds)
+    }
+
+    fn save_guild(&self, guild: GuildJson) -> AyakaResult<()> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO guilds (guild_id, music_channel, channel_setup, stay_connected, idle_source, music_message, prefix)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (guild_id) DO UPDATE SET
+                music_channel = excluded.music_channel,
+                channel_setup = excluded.channel_setup,
+                stay_connected = excluded.stay_connected,
+                idle_source = excluded.idle_source,
+                music_message = excluded.music_message,
+                prefix = excluded.prefix",
+            params![
+                guild.guild_id as i64,
+                guild.music_channel.map(|id| id as i64),
+                guild.channel_setup,
+                guild.stay_connected.map(|id| id as i64),
+                guild.idle_source,
+                guild.music_message.map(|id| id as i64),
+                guild.prefix
+            ]
+        )?;
+        transaction.execute("DELETE FROM guild_players WHERE guild_id = ?1", params![guild.guild_id as i64])?;
+        for player in &guild.players {
+            transaction.execute(
+                "INSERT INTO guild_players (channel_id, guild_id, voice_channel, message_id) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    player.channel as i64,
+                    guild.guild_id as i64,
+                    player.voice_channel as i64,
+                    player.message.map(|id| id as i64)
+                ]
+            )?;
+        }
+        let mut saved = self.saved.lock();
+        let previous = saved.get(&GuildId(guild.guild_id));
+
+        // Members only change while they use the bot, so most of them are already up to date
+        let mut members = HashMap::new();
+        for member in &guild.members {
+            if previous.and_then(|previous| previous.members.get(&member.user_id)) != Some(member) {
+                save_member(&transaction, guild.guild_id, member)?;
+            }
+            members.insert(member.user_id, member.clone());
+        }
+        for user_id in previous.into_iter().flat_map(|previous| previous.members.keys()).filter(|user_id| !members.contains_key(user_id)) {
+            transaction.execute("DELETE FROM members WHERE guild_id = ?1 AND user_id = ?2", params![guild.guild_id as i64, *user_id as i64])?;
+        }
+
+        // Plays are only ever appended, everything after the newest saved one is new
+        let new_plays = match previous.and_then(|previous| previous.last_play.as_ref()) {
+            None => &guild.history[..],
+            Some(last_play) => match guild.history.iter().rposition(|play| play == last_play) {
+                Some(index) => &guild.history[index + 1..],
+                // Rotated out of memory since the last save, so all of them are newer
+                None => &guild.history[..]
+            }
+        };
+        for play in new_plays {
+            transaction.execute(
+                "INSERT INTO play_history (guild_id, number, played_at, requester_id, title, url, duration_secs) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    guild.guild_id as i64,
+                    play.number as i64,
+                    play.played_at,
+                    play.requester.map(|id| id as i64),
+                    play.title,
+                    play.url,
+                    play.duration_secs.map(|secs| secs as i64)
+                ]
+            )?;
+        }
+        if !new_plays.is_empty() {
+            // Plays older than the newest MAX_PLAY_HISTORY would never be loaded again
+            let oldest_kept: Option<i64> = transaction.query_row(
+                "SELECT id FROM play_history WHERE guild_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2",
+                params![guild.guild_id as i64, MAX_PLAY_HISTORY as i64 - 1],
+                |row| row.get(0)
+            ).optional()?;
+            if let Some(oldest_kept) = oldest_kept {
+                transaction.execute("DELETE FROM play_history WHERE guild_id = ?1 AND id < ?2", params![guild.guild_id as i64, oldest_kept])?;
+            }
+        }
+        transaction.commit()?;
+        saved.insert(GuildId(guild.guild_id), SavedRows { members, last_play: guild.history.last().cloned() });
+        Ok(())
+    }
+
+    fn delete_guild(&self, guild_id: GuildId) -> AyakaResult<()> {
+        let connection = self.connection.lock();
+        connection.execute("DELETE FROM guilds WHERE guild_id = ?1", params![guild_id.0 as i64])?;
+        self.saved.lock().remove(&guild_id);
+        Ok(())
+    }
+
+    /// Every write is committed immediately
+    fn flush(&self) -> AyakaResult<()> {
+        Ok(())
+    }
+}
+
+/// Members of every guild by guild id, in user id order like [crate::member::MemberManager::to_json] saves them
+fn load_members(connection: &Connection) -> rusqlite::Result<HashMap<u64, Vec<MemberJson>>> {
+    let mut members = HashMap::<(u64, u64), MemberJson>::new();
+    let mut statement = connection.prepare(
+        "SELECT guild_id, user_id, tracks_requested, listening_secs, volume, dm_now_playing FROM members"
+    )?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, MemberJson {
+        user_id: row.get::<_, i64>(1)? as u64,
+        tracks_requested: row.get::<_, i64>(2)? as u64,
+        listening_secs: row.get::<_, i64>(3)? as u64,
+        volume: row.get(4)?,
+        dm_now_playing: row.get(5)?,
+        ..Default::default()
+    })))?;
+    for row in rows {
+        let (guild_id, member) = row?;
+        members.insert((guild_id, member.user_id), member);
+    }
+
+    // Most requested first, the order the counts are kept in
+    let mut statement = connection.prepare(
+        "SELECT guild_id, user_id, title, requests FROM member_tracks ORDER BY requests DESC, title"
+    )?;
+    for row in statement.query_map([], counted_row)? {
+        let (key, title, requests) = row?;
+        if let Some(member) = members.get_mut(&key) {
+            member.tracks.push((title, requests));
+        }
+    }
+    let mut statement = connection.prepare(
+        "SELECT guild_id, user_id, artist, requests FROM member_artists ORDER BY requests DESC, artist"
+    )?;
+    for row in statement.query_map([], counted_row)? {
+        let (key, artist, requests) = row?;
+        if let Some(member) = members.get_mut(&key) {
+            member.artists.push((artist, requests));
+        }
+    }
+    // Oldest block first, it is the one dropped when the list is full
+    let mut statement = connection.prepare("SELECT guild_id, user_id, url FROM member_blocked ORDER BY rowid")?;
+    let rows = statement.query_map([], |row| Ok(((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64), row.get::<_, String>(2)?)))?;
+    for row in rows {
+        let (key, url) = row?;
+        if let Some(member) = members.get_mut(&key) {
+            member.blocked.push(url);
+        }
+    }
+
+    let mut by_guild = HashMap::<u64, Vec<MemberJson>>::new();
+    for ((guild_id, _), member) in members {
+        by_guild.entry(guild_id).or_default().push(member);
+    }
+    for guild_members in by_guild.values_mut() {
+        guild_members.sort_by_key(|member| member.user_id);
+    }
+    Ok(by_guild)
+}
+
+fn counted_row(row: &rusqlite::Row) -> rusqlite::Result<((u64, u64), String, u64)> {
+    Ok(((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64), row.get(2)?, row.get::<_, i64>(3)? as u64))
+}
+
+/// Replaces everything stored about a member, their counts and blocklist are rewritten as a whole
+fn save_member(transaction: &Transaction, guild_id: u64, member: &MemberJson) -> rusqlite::Result<()> {
+    let (guild_id, user_id) = (guild_id as i64, member.user_id as i64);
+    transaction.execute(
+        "INSERT INTO members (guild_id, user_id, tracks_requested, listening_secs, volume, dm_now_playing)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (guild_id, user_id) DO UPDATE SET
+            tracks_requested = excluded.tracks_requested,
+            listening_secs = excluded.listening_secs,
+            volume = excluded.volume,
+            dm_now_playing = excluded.dm_now_playing",
+        params![guild_id, user_id, member.tracks_requested as i64, member.listening_secs as i64, member.volume, member.dm_now_playing]
+    )?;
+    for table in ["member_tracks", "member_artists", "member_blocked"] {
+        transaction.execute(&format!("DELETE FROM {} WHERE guild_id = ?1 AND user_id = ?2", table), params![guild_id, user_id])?;
+    }
+    for (title, requests) in &member.tracks {
+        transaction.execute(
+            "INSERT INTO member_tracks (guild_id, user_id, title, requests) VALUES (?1, ?2, ?3, ?4)",
+            params![guild_id, user_id, title, *requests as i64]
+        )?;
+    }
+    for (artist, requests) in &member.artists {
+        transaction.execute(
+            "INSERT INTO member_artists (guild_id, user_id, artist, requests) VALUES (?1, ?2, ?3, ?4)",
+            params![guild_id, user_id, artist, *requests as i64]
+        )?;
+    }
+    for url in &member.blocked {
+        transaction.execute(
+            "INSERT INTO member_blocked (guild_id, user_id, url) VALUES (?1, ?2, ?3)",
+            params![guild_id, user_id, url]
+        )?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::json::{GuildJson, HistoryJson, MemberJson, PlayerJson};
+    use crate::music::history::MAX_PLAY_HISTORY;
+    use crate::storage::GuildStore;
+    use super::SqliteGuildStore;
+
+    fn play(played_at: i64) -> HistoryJson {
+        HistoryJson { played_at, title: Some(format!("play {}", played_at)), ..Default::default() }
+    }
+
+    fn history_rows(store: &SqliteGuildStore) -> i64 {
+        store.connection.lock().query_row("SELECT COUNT(*) FROM play_history", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn players_survive_a_save() {
+        let store = SqliteGuildStore::open(":memory:").unwrap();
+        let mut guild = GuildJson {
+            guild_id: 1,
+            players: vec![
+                PlayerJson { channel: 2, voice_channel: 3, message: Some(4) },
+                PlayerJson { channel: 5, voice_channel: 6, message: None }
+            ],
+            ..Default::default()
+        };
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap(), vec![guild.clone()]);
+
+        // A removed channel stays removed
+        guild.players.remove(0);
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap(), vec![guild]);
+    }
+
+    #[test]
+    fn history_is_appended_and_trimmed() {
+        let store = SqliteGuildStore::open(":memory:").unwrap();
+        let mut guild = GuildJson { guild_id: 1, history: (0..3).map(play).collect(), ..Default::default() };
+        store.save_guild(guild.clone()).unwrap();
+        // Saving again without new plays writes nothing twice
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(history_rows(&store), 3);
+
+        guild.history.extend((3..5).map(play));
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap()[0].history, guild.history);
+
+        // Memory only keeps the newest plays, the database follows
+        guild.history = (0..MAX_PLAY_HISTORY as i64 + 20).map(play).skip(20).collect();
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(history_rows(&store), MAX_PLAY_HISTORY as i64);
+        assert_eq!(store.load_guilds().unwrap()[0].history, guild.history);
+    }
+
+    #[test]
+    fn members_are_updated_in_place() {
+        let store = SqliteGuildStore::open(":memory:").unwrap();
+        let member = |user_id, tracks_requested| MemberJson { user_id, tracks_requested, ..Default::default() };
+        let mut guild = GuildJson { guild_id: 1, members: vec![member(2, 1), member(3, 1)], ..Default::default() };
+        store.save_guild(guild.clone()).unwrap();
+
+        guild.members = vec![member(2, 5)];
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap()[0].members, guild.members);
+    }
+
+    #[test]
+    fn member_counts_and_blocklist_survive_a_save() {
+        let store = SqliteGuildStore::open(":memory:").unwrap();
+        let mut guild = GuildJson {
+            guild_id: 1,
+            members: vec![MemberJson {
+                user_id: 2,
+                tracks_requested: 3,
+                listening_secs: 600,
+                tracks: vec![(String::from("b"), 2), (String::from("a"), 1), (String::from("c"), 1)],
+                artists: vec![(String::from("artist"), 3)],
+                volume: Some(80),
+                dm_now_playing: true,
+                blocked: vec![String::from("https://example.com/z"), String::from("https://example.com/a")]
+            }],
+            ..Default::default()
+        };
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap()[0].members, guild.members);
+
+        // Child rows are replaced rather than added to
+        guild.members[0].tracks.remove(0);
+        guild.members[0].blocked.remove(0);
+        store.save_guild(guild.clone()).unwrap();
+        assert_eq!(store.load_guilds().unwrap()[0].members, guild.members);
+
+        guild.members.clear();
+        store.save_guild(guild).unwrap();
+        let orphans: i64 = store.connection.lock().query_row("SELECT COUNT(*) FROM member_tracks", [], |row| row.get(0)).unwrap();
+        assert_eq!(orphans, 0, "a removed member left their counts behind");
+    }
+}